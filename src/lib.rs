@@ -0,0 +1,4362 @@
+//! Download and upload page views kept in a Cloudflare KV namespace
+
+use anyhow::{Context, Result};
+use base64::{engine::general_purpose::STANDARD as BASE64, Engine};
+use chrono::{DateTime, NaiveDate, Utc};
+use csv::{Reader, Writer, WriterBuilder};
+use futures::{
+    future,
+    stream::{self, LocalBoxStream, StreamExt},
+    Future,
+};
+use governor::{DefaultDirectRateLimiter, Quota};
+use indicatif::ProgressBar;
+use log::{debug, info, warn};
+use percent_encoding::{percent_decode_str, utf8_percent_encode, NON_ALPHANUMERIC};
+use rand::Rng;
+use regex::Regex;
+use reqwest::{
+    header::RETRY_AFTER, multipart::Form, Client, Method, RequestBuilder, Response, StatusCode,
+};
+use rusqlite::{params, Connection};
+use serde::{
+    de::{DeserializeOwned, IgnoredAny},
+    Deserialize, Serialize,
+};
+use serde_json::{json, Value};
+use std::{
+    cmp::Ordering,
+    collections::{BTreeMap, BTreeSet, HashMap},
+    convert::TryFrom,
+    fmt,
+    fs::{self, File, OpenOptions},
+    io::{self, BufReader, BufWriter, ErrorKind, Write},
+    num::NonZeroU32,
+    ops::RangeInclusive,
+    path::{Path, PathBuf},
+    str::FromStr,
+    sync::{
+        atomic::{AtomicBool, AtomicUsize, Ordering as AtomicOrdering},
+        Arc, Mutex, PoisonError,
+    },
+    time::{Duration, Instant},
+};
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum OutputFormat {
+    Csv,
+    Tsv,
+    Json,
+    Ndjson,
+    Sqlite,
+    /// Excel workbook, only written when built with the `xlsx` feature
+    Xlsx,
+    /// Aligned columns for reading in a terminal
+    Table,
+    /// Text exposition format for the node_exporter textfile collector
+    Prometheus,
+}
+
+impl OutputFormat {
+    pub const VARIANTS: &'static [&'static str] = &[
+        "csv",
+        "tsv",
+        "json",
+        "ndjson",
+        "sqlite",
+        "xlsx",
+        "table",
+        "prometheus",
+    ];
+
+    /// Media type of the written output, as told to object storage
+    pub fn content_type(self) -> &'static str {
+        match self {
+            OutputFormat::Csv => "text/csv",
+            OutputFormat::Tsv => "text/tab-separated-values",
+            OutputFormat::Json => "application/json",
+            OutputFormat::Ndjson => "application/x-ndjson",
+            OutputFormat::Sqlite => "application/vnd.sqlite3",
+            OutputFormat::Xlsx => {
+                "application/vnd.openxmlformats-officedocument.spreadsheetml.sheet"
+            }
+            OutputFormat::Table | OutputFormat::Prometheus => "text/plain",
+        }
+    }
+}
+
+impl FromStr for OutputFormat {
+    type Err = anyhow::Error;
+
+    fn from_str(value: &str) -> Result<Self> {
+        match value {
+            "csv" => Ok(OutputFormat::Csv),
+            "tsv" => Ok(OutputFormat::Tsv),
+            "json" => Ok(OutputFormat::Json),
+            "ndjson" => Ok(OutputFormat::Ndjson),
+            "sqlite" => Ok(OutputFormat::Sqlite),
+            "xlsx" => Ok(OutputFormat::Xlsx),
+            "table" => Ok(OutputFormat::Table),
+            "prometheus" => Ok(OutputFormat::Prometheus),
+            _ => anyhow::bail!("unknown output format {}", value),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub enum Auth {
+    /// API token sent as a bearer token
+    Token(String),
+    /// Global API Key sent with the account email
+    Key { email: String, key: String },
+}
+
+/// Keeps keys whose embedded date falls within a range
+#[derive(Debug, Clone)]
+pub struct DateFilter {
+    since: Option<NaiveDate>,
+    until: Option<NaiveDate>,
+    date_regex: Regex,
+    keep_undated: bool,
+}
+
+impl DateFilter {
+    pub fn new(
+        since: Option<NaiveDate>,
+        until: Option<NaiveDate>,
+        date_regex: Regex,
+        keep_undated: bool,
+    ) -> Result<Self> {
+        if !date_regex.capture_names().any(|name| name == Some("date")) {
+            anyhow::bail!("date regex {} has no `date` capture group", date_regex);
+        }
+
+        Ok(DateFilter {
+            since,
+            until,
+            date_regex,
+            keep_undated,
+        })
+    }
+
+    pub fn matches(&self, key: &str) -> bool {
+        let date = self
+            .date_regex
+            .captures(key)
+            .and_then(|captures| captures.name("date"))
+            .and_then(|date| date.as_str().parse::<NaiveDate>().ok());
+
+        match date {
+            Some(date) => {
+                self.since.is_none_or(|since| date >= since)
+                    && self.until.is_none_or(|until| date <= until)
+            }
+            None => self.keep_undated,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Compress {
+    None,
+    Gzip,
+}
+
+impl Compress {
+    pub const VARIANTS: &'static [&'static str] = &["none", "gzip"];
+}
+
+impl FromStr for Compress {
+    type Err = anyhow::Error;
+
+    fn from_str(value: &str) -> Result<Self> {
+        match value {
+            "none" => Ok(Compress::None),
+            "gzip" => Ok(Compress::Gzip),
+            _ => anyhow::bail!("unknown compression {}", value),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SortKey {
+    Key,
+    Views,
+    None,
+}
+
+impl SortKey {
+    pub const VARIANTS: &'static [&'static str] = &["key", "views", "none"];
+}
+
+impl FromStr for SortKey {
+    type Err = anyhow::Error;
+
+    fn from_str(value: &str) -> Result<Self> {
+        match value {
+            "key" => Ok(SortKey::Key),
+            "views" => Ok(SortKey::Views),
+            "none" => Ok(SortKey::None),
+            _ => anyhow::bail!("unknown sort key {}", value),
+        }
+    }
+}
+
+/// Most decimals `--precision` can round percentages to
+pub const MAX_PRECISION: usize = 10;
+
+/// Round to `precision` decimals
+fn round_to(value: f64, precision: usize) -> f64 {
+    let scale = 10f64.powi(precision as i32);
+
+    (value * scale).round() / scale
+}
+
+/// JSON number of a rounded percentage, where whole ones are integers instead of `50.0`
+fn percent_value(percent: f64) -> Value {
+    if percent.fract() == 0.0 {
+        json!(percent as u64)
+    } else {
+        json!(percent)
+    }
+}
+
+fn serialize_percent<S: serde::Serializer>(
+    percent: &Option<f64>,
+    serializer: S,
+) -> Result<S::Ok, S::Error> {
+    percent.map(percent_value).serialize(serializer)
+}
+
+/// Field of a record written as a CSV column or JSON field
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Column {
+    Namespace,
+    Path,
+    Views,
+    Rank,
+    Percent,
+    Expiration,
+}
+
+impl Column {
+    pub const VARIANTS: &'static [&'static str] = &[
+        "namespace",
+        "path",
+        "views",
+        "rank",
+        "percent",
+        "expiration",
+    ];
+
+    fn name(self) -> &'static str {
+        match self {
+            Column::Namespace => "namespace",
+            Column::Path => "path",
+            Column::Views => "views",
+            Column::Rank => "rank",
+            Column::Percent => "percent",
+            Column::Expiration => "expiration",
+        }
+    }
+
+    fn text(self, record: &CsvRecord, precision: usize) -> String {
+        match self {
+            Column::Namespace => record.namespace.clone().unwrap_or_default(),
+            Column::Path => record.path.clone(),
+            Column::Views => record.views.to_string(),
+            Column::Rank => record.rank.map(|rank| rank.to_string()).unwrap_or_default(),
+            Column::Percent => record
+                .percent
+                .map(|percent| format!("{:.*}", precision, percent))
+                .unwrap_or_default(),
+            Column::Expiration => record
+                .expiration
+                .map(|expiration| expiration.to_string())
+                .unwrap_or_default(),
+        }
+    }
+
+    fn value(self, record: &CsvRecord) -> Value {
+        match self {
+            Column::Namespace => json!(record.namespace),
+            Column::Path => json!(record.path),
+            Column::Views => json!(record.views),
+            Column::Rank => json!(record.rank),
+            Column::Percent => json!(record.percent.map(percent_value)),
+            Column::Expiration => json!(record.expiration),
+        }
+    }
+}
+
+impl FromStr for Column {
+    type Err = anyhow::Error;
+
+    fn from_str(value: &str) -> Result<Self> {
+        match value {
+            "namespace" => Ok(Column::Namespace),
+            "path" => Ok(Column::Path),
+            "views" => Ok(Column::Views),
+            "rank" => Ok(Column::Rank),
+            "percent" => Ok(Column::Percent),
+            "expiration" => Ok(Column::Expiration),
+            _ => anyhow::bail!("unknown column {}", value),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SortOrder {
+    Asc,
+    Desc,
+}
+
+impl SortOrder {
+    pub const VARIANTS: &'static [&'static str] = &["asc", "desc"];
+}
+
+impl FromStr for SortOrder {
+    type Err = anyhow::Error;
+
+    fn from_str(value: &str) -> Result<Self> {
+        match value {
+            "asc" => Ok(SortOrder::Asc),
+            "desc" => Ok(SortOrder::Desc),
+            _ => anyhow::bail!("unknown sort order {}", value),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum DiffFormat {
+    Csv,
+    Table,
+    Json,
+}
+
+impl DiffFormat {
+    pub const VARIANTS: &'static [&'static str] = &["csv", "table", "json"];
+}
+
+impl FromStr for DiffFormat {
+    type Err = anyhow::Error;
+
+    fn from_str(value: &str) -> Result<Self> {
+        match value {
+            "csv" => Ok(DiffFormat::Csv),
+            "table" => Ok(DiffFormat::Table),
+            "json" => Ok(DiffFormat::Json),
+            _ => anyhow::bail!("unknown diff format {}", value),
+        }
+    }
+}
+
+/// What to do with rows of a read CSV file that repeat a path of the same namespace
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum DuplicatePolicy {
+    Error,
+    /// Keep the views of the last row, such as the latest snapshot of an appended file
+    Last,
+    Sum,
+}
+
+impl DuplicatePolicy {
+    pub const VARIANTS: &'static [&'static str] = &["error", "last", "sum"];
+}
+
+impl FromStr for DuplicatePolicy {
+    type Err = anyhow::Error;
+
+    fn from_str(value: &str) -> Result<Self> {
+        match value {
+            "error" => Ok(DuplicatePolicy::Error),
+            "last" => Ok(DuplicatePolicy::Last),
+            "sum" => Ok(DuplicatePolicy::Sum),
+            _ => anyhow::bail!("unknown duplicate policy {}", value),
+        }
+    }
+}
+
+/// How the views of a path found in several merged snapshots are combined
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum MergeStrategy {
+    Sum,
+    Max,
+    /// Views of the last snapshot with the path
+    Last,
+}
+
+impl MergeStrategy {
+    pub const VARIANTS: &'static [&'static str] = &["sum", "max", "last"];
+}
+
+impl FromStr for MergeStrategy {
+    type Err = anyhow::Error;
+
+    fn from_str(value: &str) -> Result<Self> {
+        match value {
+            "sum" => Ok(MergeStrategy::Sum),
+            "max" => Ok(MergeStrategy::Max),
+            "last" => Ok(MergeStrategy::Last),
+            _ => anyhow::bail!("unknown merge strategy {}", value),
+        }
+    }
+}
+
+impl Auth {
+    pub fn from_parts(
+        jwt: Option<String>,
+        api_email: Option<String>,
+        api_key: Option<String>,
+    ) -> Result<Self> {
+        match (jwt, api_email, api_key) {
+            (Some(jwt), _, _) => Ok(Auth::Token(jwt)),
+            (None, Some(email), Some(key)) => Ok(Auth::Key { email, key }),
+            _ => anyhow::bail!("Either --jwt or both --api-email and --api-key are required"),
+        }
+    }
+}
+
+/// API base URL, authentication, account id and KV id
+pub type Credential = (String, Auth, String, String);
+
+fn authorize(req: RequestBuilder, auth: &Auth) -> RequestBuilder {
+    match auth {
+        Auth::Token(jwt) => req.bearer_auth(jwt),
+        Auth::Key { email, key } => req.header("X-Auth-Email", email).header("X-Auth-Key", key),
+    }
+}
+
+/// Envelope wrapping every Cloudflare API response
+#[derive(Debug, Deserialize)]
+struct CloudflareResponse<T> {
+    success: bool,
+    #[serde(default)]
+    errors: Vec<CloudflareMessage>,
+    #[serde(default)]
+    messages: Vec<CloudflareMessage>,
+    result: Option<T>,
+    #[serde(default)]
+    result_info: ResultInfo,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct CloudflareMessage {
+    pub code: i64,
+    pub message: String,
+}
+
+impl fmt::Display for CloudflareMessage {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}: {}", self.code, self.message)
+    }
+}
+
+#[derive(Debug, Default, Deserialize)]
+struct ResultInfo {
+    #[serde(default)]
+    cursor: String,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ListKey {
+    pub name: String,
+    pub expiration: Option<u64>,
+    pub metadata: Option<Value>,
+}
+
+#[derive(Debug, Serialize)]
+struct BulkGetBody<'a> {
+    keys: &'a [String],
+    #[serde(rename = "type")]
+    value_type: &'static str,
+}
+
+#[derive(Debug, Deserialize)]
+struct BulkGetResult {
+    values: HashMap<String, Value>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum DiffStatus {
+    Added,
+    Removed,
+    Increased,
+    Decreased,
+}
+
+impl fmt::Display for DiffStatus {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match self {
+            DiffStatus::Added => "added",
+            DiffStatus::Removed => "removed",
+            DiffStatus::Increased => "increased",
+            DiffStatus::Decreased => "decreased",
+        })
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct DiffRecord {
+    /// KV id of the page, only set when the snapshots have several namespaces
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub namespace: Option<String>,
+    pub path: String,
+    pub status: DiffStatus,
+    pub from: Option<u64>,
+    pub to: Option<u64>,
+    pub change: i64,
+}
+
+#[derive(Debug, Serialize)]
+struct BulkWritePair<'a> {
+    key: &'a str,
+    value: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    metadata: Option<&'a Value>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    expiration_ttl: Option<u64>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CsvRecord {
+    /// KV id the key came from, only set when downloading several namespaces
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub namespace: Option<String>,
+    pub path: String,
+    pub views: u64,
+    /// Position by views where ties share a rank, only set by `add_stats`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub rank: Option<usize>,
+    /// Share of the total views rounded to the `add_stats` precision, only set by `add_stats`
+    #[serde(
+        default,
+        skip_serializing_if = "Option::is_none",
+        serialize_with = "serialize_percent"
+    )]
+    pub percent: Option<f64>,
+    /// Key expiration as seconds since the epoch, only set with `--include-metadata`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub expiration: Option<u64>,
+    /// Chosen fields of the key metadata, only set with `--include-metadata`
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub metadata: BTreeMap<String, Value>,
+}
+
+#[derive(Debug)]
+pub struct HttpStatusError {
+    pub status: StatusCode,
+    pub retry_after: Option<Duration>,
+}
+
+impl fmt::Display for HttpStatusError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Unexpected HTTP status {}", self.status)
+    }
+}
+
+impl std::error::Error for HttpStatusError {}
+
+/// A single value request outlasted `DownloadOptions::timeout_per_key`
+#[derive(Debug)]
+pub struct KeyTimeoutError {
+    pub timeout: Duration,
+}
+
+impl fmt::Display for KeyTimeoutError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "No value within the per-key timeout of {:.1}s",
+            self.timeout.as_secs_f64()
+        )
+    }
+}
+
+impl std::error::Error for KeyTimeoutError {}
+
+#[derive(Debug)]
+pub struct KeyNotFound {
+    pub key: String,
+}
+
+impl fmt::Display for KeyNotFound {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Key {} does not exist", self.key)
+    }
+}
+
+impl std::error::Error for KeyNotFound {}
+
+/// Failed response that carried the Cloudflare `errors` array
+#[derive(Debug)]
+pub struct CloudflareError {
+    pub status: StatusCode,
+    pub retry_after: Option<Duration>,
+    pub errors: Vec<CloudflareMessage>,
+}
+
+impl fmt::Display for CloudflareError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if self.errors.is_empty() {
+            return write!(f, "Cloudflare error with HTTP status {}", self.status);
+        }
+
+        for (index, error) in self.errors.iter().enumerate() {
+            if index > 0 {
+                write!(f, "; ")?;
+            }
+
+            write!(f, "Cloudflare error {}", error)?;
+        }
+
+        Ok(())
+    }
+}
+
+impl std::error::Error for CloudflareError {}
+
+/// Wait asked for by `Retry-After` in seconds or as an HTTP date, where past dates wait for nothing
+fn retry_after(resp: &Response) -> Option<Duration> {
+    let value = resp.headers().get(RETRY_AFTER)?.to_str().ok()?.trim();
+
+    if let Ok(secs) = value.parse::<u64>() {
+        return Some(Duration::from_secs(secs));
+    }
+
+    let retry_at = DateTime::parse_from_rfc2822(value).ok()?;
+
+    Some(
+        (retry_at.with_timezone(&Utc) - Utc::now())
+            .to_std()
+            .unwrap_or_default(),
+    )
+}
+
+async fn check_status(resp: Response) -> Result<Response> {
+    let status = resp.status();
+
+    if status.is_success() {
+        return Ok(resp);
+    }
+
+    let retry_after = retry_after(&resp);
+    let body = resp.bytes().await?;
+
+    match serde_json::from_slice::<CloudflareResponse<IgnoredAny>>(&body) {
+        Ok(envelope) if !envelope.errors.is_empty() => Err(CloudflareError {
+            status,
+            retry_after,
+            errors: envelope.errors,
+        }
+        .into()),
+        _ => Err(HttpStatusError {
+            status,
+            retry_after,
+        }
+        .into()),
+    }
+}
+
+async fn read_envelope<T: DeserializeOwned>(resp: Response) -> Result<CloudflareResponse<T>> {
+    let status = resp.status();
+    let envelope = check_status(resp)
+        .await?
+        .json::<CloudflareResponse<T>>()
+        .await?;
+
+    if !envelope.success {
+        return Err(CloudflareError {
+            status,
+            retry_after: None,
+            errors: envelope.errors,
+        }
+        .into());
+    }
+
+    for message in &envelope.messages {
+        info!("Cloudflare message {}", message);
+    }
+
+    Ok(envelope)
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct RetryPolicy {
+    pub max_retries: u32,
+    pub base_delay: Duration,
+}
+
+impl RetryPolicy {
+    /// Exponential backoff with up to one `base_delay` of random jitter
+    fn backoff(&self, attempt: u32) -> Duration {
+        let exponential = self.base_delay * 2u32.saturating_pow(attempt);
+        let jitter_ms = rand::thread_rng().gen_range(0..=self.base_delay.as_millis() as u64);
+
+        exponential + Duration::from_millis(jitter_ms)
+    }
+
+    /// Delay before the next attempt after a failure that did not ask for one
+    ///
+    /// Connection failures never reached the API, so there is no load to back off from and they
+    /// are retried after a single `base_delay` instead.
+    fn delay(&self, category: FailureCategory, attempt: u32) -> Duration {
+        match category {
+            FailureCategory::Connect => self.backoff(0),
+            _ => self.backoff(attempt),
+        }
+    }
+}
+
+/// Why a request failed, which decides whether and how soon it is retried
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum FailureCategory {
+    /// The API host name did not resolve
+    Dns,
+    /// The connection was refused, reset or could not be opened
+    Connect,
+    /// The TLS handshake failed, which is the rest of connecting to an `https` URL
+    Tls,
+    /// The request or the connection took too long
+    Timeout,
+    /// The connection failed while sending the request or reading the response
+    Transport,
+    /// The API answered with 429
+    RateLimited,
+    /// The API answered with a 5xx status
+    Server,
+    /// The API answered with any other failed status, such as a 4xx, which repeats on retry
+    Client,
+}
+
+impl fmt::Display for FailureCategory {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match self {
+            FailureCategory::Dns => "DNS",
+            FailureCategory::Connect => "connection",
+            FailureCategory::Tls => "TLS",
+            FailureCategory::Timeout => "timeout",
+            FailureCategory::Transport => "transport",
+            FailureCategory::RateLimited => "rate limit",
+            FailureCategory::Server => "server",
+            FailureCategory::Client => "client",
+        })
+    }
+}
+
+/// Category of a failed connection, told apart by the types of the errors reqwest wraps
+fn connect_category(err: &reqwest::Error) -> FailureCategory {
+    let mut source = std::error::Error::source(err);
+
+    while let Some(cause) = source {
+        // Failed handshakes of the TLS backend come before any I/O error they wrap
+        if cause.is::<native_tls::Error>() {
+            return FailureCategory::Tls;
+        }
+
+        if let Some(io_err) = cause.downcast_ref::<io::Error>() {
+            return match io_err.kind() {
+                ErrorKind::TimedOut => FailureCategory::Timeout,
+                ErrorKind::ConnectionRefused
+                | ErrorKind::ConnectionReset
+                | ErrorKind::ConnectionAborted
+                | ErrorKind::NotConnected
+                | ErrorKind::AddrNotAvailable
+                | ErrorKind::HostUnreachable
+                | ErrorKind::NetworkUnreachable
+                | ErrorKind::NetworkDown => FailureCategory::Connect,
+                // Failed lookups of the resolver carry no OS error code, unlike failed sockets
+                _ if io_err.raw_os_error().is_none() => FailureCategory::Dns,
+                _ => FailureCategory::Connect,
+            };
+        }
+
+        source = cause.source();
+    }
+
+    FailureCategory::Connect
+}
+
+/// Category of a failed request, or nothing for errors not about a request such as parsing
+fn failure_category(err: &anyhow::Error) -> Option<FailureCategory> {
+    if let Some((status, _)) = response_error(err) {
+        return Some(if status == StatusCode::TOO_MANY_REQUESTS {
+            FailureCategory::RateLimited
+        } else if status.is_server_error() {
+            FailureCategory::Server
+        } else {
+            FailureCategory::Client
+        });
+    }
+
+    if err.downcast_ref::<KeyTimeoutError>().is_some() {
+        return Some(FailureCategory::Timeout);
+    }
+
+    let err = err.downcast_ref::<reqwest::Error>()?;
+
+    if err.is_timeout() {
+        Some(FailureCategory::Timeout)
+    } else if err.is_connect() {
+        Some(connect_category(err))
+    } else if err.is_request() || err.is_body() {
+        Some(FailureCategory::Transport)
+    } else {
+        None
+    }
+}
+
+/// Status and requested delay of a failed API response
+fn response_error(err: &anyhow::Error) -> Option<(StatusCode, Option<Duration>)> {
+    if let Some(err) = err.downcast_ref::<HttpStatusError>() {
+        Some((err.status, err.retry_after))
+    } else {
+        err.downcast_ref::<CloudflareError>()
+            .map(|err| (err.status, err.retry_after))
+    }
+}
+
+/// Whether the API answered with a 404, such as for a missing key
+pub fn is_not_found(err: &anyhow::Error) -> bool {
+    response_error(err).is_some_and(|(status, _)| status == StatusCode::NOT_FOUND)
+}
+
+/// Whether the API refused the credentials or their permissions
+pub fn is_unauthorized(err: &anyhow::Error) -> bool {
+    response_error(err).is_some_and(|(status, _)| {
+        status == StatusCode::UNAUTHORIZED || status == StatusCode::FORBIDDEN
+    })
+}
+
+/// Whether a request failed before the API answered
+pub fn is_network_error(err: &anyhow::Error) -> bool {
+    err.chain().any(|cause| {
+        cause
+            .downcast_ref::<reqwest::Error>()
+            .is_some_and(|err| err.is_connect() || err.is_timeout() || err.is_request())
+    })
+}
+
+/// Category of an error worth retrying and how long the server asked to wait
+fn retry_hint(err: &anyhow::Error) -> Option<(FailureCategory, Option<Duration>)> {
+    let category = failure_category(err)?;
+
+    match category {
+        FailureCategory::Client => None,
+        FailureCategory::RateLimited | FailureCategory::Server => Some((
+            category,
+            response_error(err).and_then(|(_, retry_after)| retry_after),
+        )),
+        _ => Some((category, None)),
+    }
+}
+
+async fn with_retry<T, F, Fut>(policy: &RetryPolicy, mut action: F) -> Result<T>
+where
+    F: FnMut() -> Fut,
+    Fut: Future<Output = Result<T>>,
+{
+    let mut attempt = 0;
+
+    loop {
+        let err = match action().await {
+            Ok(value) => return Ok(value),
+            Err(err) => err,
+        };
+
+        let (category, retry_after) = match retry_hint(&err) {
+            Some((category, retry_after)) if attempt < policy.max_retries => {
+                (category, retry_after)
+            }
+            Some((category, _)) => {
+                debug!("Giving up on {} error after {} retries", category, attempt);
+
+                return Err(err);
+            }
+            None => {
+                if let Some(category) = failure_category(&err) {
+                    debug!("Not retrying {} error: {:#}", category, &err);
+                }
+
+                return Err(err);
+            }
+        };
+
+        debug!(
+            "Attempt {} failed with {} error: {:#}",
+            attempt + 1,
+            category,
+            &err
+        );
+
+        let delay = match retry_after {
+            Some(delay) => {
+                warn!(
+                    "Retrying in {}ms as asked by Retry-After after {} error: {}",
+                    delay.as_millis(),
+                    category,
+                    &err
+                );
+
+                delay
+            }
+            None => {
+                let delay = policy.delay(category, attempt);
+
+                warn!(
+                    "Retrying in {}ms after {} error: {}",
+                    delay.as_millis(),
+                    category,
+                    &err
+                );
+
+                delay
+            }
+        };
+
+        tokio::time::sleep(delay).await;
+
+        attempt += 1;
+    }
+}
+
+/// Mark timeouts so they are not mistaken for other request failures
+pub fn timeout_context(err: anyhow::Error) -> anyhow::Error {
+    match err.downcast_ref::<reqwest::Error>() {
+        Some(req_err) if req_err.is_timeout() => err.context("Cloudflare API request timed out"),
+        _ => err,
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct TokenStatus {
+    status: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct Namespace {
+    title: String,
+}
+
+/// Explain an answer from the API, leaving connection failures as they are
+pub fn rejected_context<C>(err: anyhow::Error, context: C) -> anyhow::Error
+where
+    C: fmt::Display + Send + Sync + 'static,
+{
+    if is_network_error(&err) {
+        err
+    } else {
+        err.context(context)
+    }
+}
+
+/// Cloudflare error codes of a request the credentials are not allowed to make
+const PERMISSION_DENIED_CODES: &[i64] = &[9109, 10000];
+
+/// Whether Cloudflare refused the request for lack of permissions
+fn is_permission_denied(err: &anyhow::Error) -> bool {
+    err.downcast_ref::<CloudflareError>().is_some_and(|err| {
+        err.errors
+            .iter()
+            .any(|error| PERMISSION_DENIED_CODES.contains(&error.code))
+    })
+}
+
+/// Point an API token that was refused to the permission every KV request needs
+fn permission_context(err: anyhow::Error, auth: &Auth, account_id: &str) -> anyhow::Error {
+    if !matches!(auth, Auth::Token(_)) || !is_permission_denied(&err) {
+        return err;
+    }
+
+    err.context(format!(
+        "The API token needs to be active with the `Account.Workers KV Storage` permission for account {}, \
+         which can be checked at https://dash.cloudflare.com/profile/api-tokens",
+        account_id
+    ))
+}
+
+/// Check the token and KV namespace with cheap requests before doing any work
+pub async fn verify_credential(client: &Client, cred: &Credential) -> Result<()> {
+    let (api_base, auth, account_id, kv_id) = cred;
+
+    if let Auth::Token(_) = auth {
+        let url = format!("{}/user/tokens/verify", api_base);
+        let token = async {
+            read_envelope::<TokenStatus>(authorize(client.get(url), auth).send().await?).await
+        }
+        .await
+        .map_err(|err| rejected_context(err, "The API token is invalid or expired"))?;
+
+        match token.result {
+            Some(TokenStatus { status }) if status == "active" => {}
+            Some(TokenStatus { status }) => anyhow::bail!("The API token is {}", status),
+            None => anyhow::bail!("The API token could not be verified"),
+        }
+    }
+
+    let url = format!(
+        "{}/accounts/{}/storage/kv/namespaces/{}",
+        api_base, account_id, kv_id
+    );
+    let namespace =
+        async { read_envelope::<Namespace>(authorize(client.get(url), auth).send().await?).await }
+            .await
+            .map_err(|err| {
+                let err = permission_context(err, auth, account_id);
+
+                rejected_context(
+                    err,
+                    format!(
+                "Could not find KV namespace {} in account {}, or the credentials cannot read it",
+                kv_id, account_id
+            ),
+                )
+            })?;
+
+    if let Some(Namespace { title }) = namespace.result {
+        info!("Verified access to KV namespace {} ({})", title, kv_id);
+    }
+
+    Ok(())
+}
+
+/// Range of keys that page sizes must fall in
+pub const LIST_PAGE_SIZES: RangeInclusive<u32> = 10..=1000;
+
+/// Query of a key listing
+#[derive(Debug, Clone, Default)]
+pub struct ListOptions {
+    /// Only list keys starting with this prefix
+    pub prefix: Option<String>,
+    /// Stop paginating once this many keys are listed
+    pub limit: Option<usize>,
+    /// Keys per page within `LIST_PAGE_SIZES`, where Cloudflare defaults to 1000
+    pub page_size: Option<u32>,
+    /// Cursor of a previous listing to continue from
+    pub start_cursor: Option<String>,
+}
+
+pub async fn list_keys(
+    client: &Client,
+    cred: &Credential,
+    options: &ListOptions,
+    tracker: Option<&RequestTracker>,
+) -> Result<Vec<ListKey>> {
+    let (api_base, auth, account_id, kv_id) = cred;
+    let url = format!(
+        "{}/accounts/{}/storage/kv/namespaces/{}/keys",
+        api_base, account_id, kv_id
+    );
+
+    let mut keys = Vec::new();
+    let mut cursor = options.start_cursor.clone().unwrap_or_default();
+
+    loop {
+        if let Some(tracker) = tracker {
+            tracker.wait(&tracker.list_requests).await?;
+        }
+
+        let mut req = authorize(client.get(&url), auth);
+
+        if let Some(prefix) = &options.prefix {
+            req = req.query(&[("prefix", prefix)]);
+        }
+
+        if let Some(page_size) = options.page_size {
+            req = req.query(&[("limit", page_size)]);
+        }
+
+        if !cursor.is_empty() {
+            debug!("Listing keys of {} from cursor {}", kv_id, cursor);
+
+            req = req.query(&[("cursor", &cursor)]);
+        }
+
+        let payload = read_envelope::<Vec<ListKey>>(req.send().await?)
+            .await
+            .map_err(|err| permission_context(err, auth, account_id))?;
+
+        keys.extend(payload.result.unwrap_or_default());
+
+        if let Some(limit) = options.limit {
+            if keys.len() >= limit {
+                keys.truncate(limit);
+                break;
+            }
+        }
+
+        if payload.result_info.cursor.is_empty() {
+            break;
+        }
+
+        cursor = payload.result_info.cursor;
+    }
+
+    Ok(keys)
+}
+
+/// List the keys under each of `prefixes` at the same time, in the order of a single listing
+///
+/// Keys listed by overlapping prefixes are only kept once. Prefixes cannot be derived, as keys sort
+/// by their bytes and no split by the character after `options.prefix` reaches those with a
+/// control, DEL or non-ASCII character there.
+pub async fn list_keys_parallel(
+    client: &Client,
+    cred: &Credential,
+    options: &ListOptions,
+    prefixes: &[String],
+    concurrency: usize,
+    tracker: Option<&RequestTracker>,
+) -> Result<Vec<ListKey>> {
+    if prefixes.is_empty() {
+        anyhow::bail!("parallel listing needs the prefixes to list");
+    }
+
+    let mut listed = BTreeMap::new();
+
+    info!(
+        "Listing keys of {} under {} prefixes, {} at a time",
+        cred.3,
+        prefixes.len(),
+        concurrency
+    );
+
+    let mut pages = stream::iter(prefixes.iter().cloned())
+        .map(|prefix| async move {
+            let options = ListOptions {
+                prefix: Some(prefix),
+                limit: None,
+                ..options.clone()
+            };
+
+            list_keys(client, cred, &options, tracker).await
+        })
+        .buffer_unordered(concurrency);
+
+    while let Some(keys) = pages.next().await {
+        listed.extend(keys?.into_iter().map(|key| (key.name.clone(), key)));
+    }
+
+    Ok(listed.into_values().collect())
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ParseErrorPolicy {
+    Skip,
+    Zero,
+    Fail,
+}
+
+impl ParseErrorPolicy {
+    pub const VARIANTS: &'static [&'static str] = &["skip", "zero", "fail"];
+}
+
+impl FromStr for ParseErrorPolicy {
+    type Err = anyhow::Error;
+
+    fn from_str(value: &str) -> Result<Self> {
+        match value {
+            "skip" => Ok(ParseErrorPolicy::Skip),
+            "zero" => Ok(ParseErrorPolicy::Zero),
+            "fail" => Ok(ParseErrorPolicy::Fail),
+            _ => anyhow::bail!("unknown parse error policy {}", value),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct ValueParseError {
+    pub key: String,
+    pub raw: String,
+}
+
+impl fmt::Display for ValueParseError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Value of {} is not a view count: {}", self.key, self.raw)
+    }
+}
+
+impl std::error::Error for ValueParseError {}
+
+/// Fetching stopped once more values failed than `DownloadOptions::max_failures`
+#[derive(Debug)]
+pub struct CircuitBreakerError {
+    pub failures: usize,
+}
+
+impl fmt::Display for CircuitBreakerError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "Cloudflare appears to be failing, stopped after {} errors",
+            self.failures
+        )
+    }
+}
+
+impl std::error::Error for CircuitBreakerError {}
+
+/// The run would make more list and value requests than `DownloadOptions::max_total_requests`
+#[derive(Debug)]
+pub struct RequestBudgetError {
+    pub max_requests: usize,
+    /// Requests the run needs at least, counting the ones made so far
+    pub needed: usize,
+}
+
+impl fmt::Display for RequestBudgetError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "Stopped as the run needs at least {} requests, more than the {} allowed",
+            self.needed, self.max_requests
+        )
+    }
+}
+
+impl std::error::Error for RequestBudgetError {}
+
+/// Some values could not be fetched and skipping them was not allowed
+#[derive(Debug)]
+pub struct FailedValuesError {
+    pub failures: usize,
+    pub total: usize,
+}
+
+impl fmt::Display for FailedValuesError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "Failed to fetch {} of {} values",
+            self.failures, self.total
+        )
+    }
+}
+
+impl std::error::Error for FailedValuesError {}
+
+/// Read a view count from an integer, a whole float or a numeric string
+fn parse_json_views(value: &Value) -> Option<u64> {
+    match value {
+        Value::Number(number) => number.as_u64().or_else(|| {
+            number
+                .as_f64()
+                .filter(|float| float.fract() == 0.0 && *float >= 0.0 && *float < u64::MAX as f64)
+                .map(|float| float as u64)
+        }),
+        Value::String(text) => serde_json::from_str::<Value>(text.trim())
+            .ok()
+            .filter(Value::is_number)
+            .and_then(|number| parse_json_views(&number)),
+        _ => None,
+    }
+}
+
+/// How the body of a value is read as a view count
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ValueType {
+    /// JSON integer, whole float or numeric string
+    Int,
+    /// Decimal digits as plain text, with any surrounding whitespace
+    Text,
+    /// Base64 of decimal digits as plain text
+    Base64Int,
+}
+
+impl ValueType {
+    pub const VARIANTS: &'static [&'static str] = &["int", "text", "base64-int"];
+
+    fn views(self, raw: &str) -> Option<u64> {
+        match self {
+            ValueType::Int => serde_json::from_str::<Value>(raw)
+                .ok()
+                .as_ref()
+                .and_then(parse_json_views),
+            ValueType::Text => raw.trim().parse().ok(),
+            ValueType::Base64Int => BASE64
+                .decode(raw.trim())
+                .ok()
+                .and_then(|bytes| String::from_utf8(bytes).ok())
+                .and_then(|text| ValueType::Text.views(&text)),
+        }
+    }
+}
+
+impl FromStr for ValueType {
+    type Err = anyhow::Error;
+
+    fn from_str(value: &str) -> Result<Self> {
+        match value {
+            "int" => Ok(ValueType::Int),
+            "text" => Ok(ValueType::Text),
+            "base64-int" => Ok(ValueType::Base64Int),
+            _ => anyhow::bail!("unknown value type {}", value),
+        }
+    }
+}
+
+fn parse_views(key: &str, raw: &str, value_type: ValueType) -> Result<u64> {
+    value_type.views(raw).ok_or_else(|| {
+        ValueParseError {
+            key: key.to_owned(),
+            raw: raw.to_owned(),
+        }
+        .into()
+    })
+}
+
+/// Record unparsable values as zero views when asked to
+fn zero_on_parse_error(
+    policy: ParseErrorPolicy,
+    res: Result<(String, u64)>,
+) -> Result<(String, u64)> {
+    match res {
+        Err(err) if policy == ParseErrorPolicy::Zero => match err.downcast::<ValueParseError>() {
+            Ok(parse_err) => {
+                warn!("{}, recording 0 views", parse_err);
+
+                Ok((parse_err.key, 0))
+            }
+            Err(err) => Err(err),
+        },
+        res => res,
+    }
+}
+
+/// URL of the value of a key, which is encoded once as a single path segment
+///
+/// Every character but ASCII letters and digits is encoded, so `/`, `?`, `#` and `%` stay part of
+/// the key and an already encoded key such as `/a%2Fb` keeps its `%`. The keys `.` and `..` are
+/// refused, as URLs resolve them as dot segments to another endpoint even when encoded.
+fn value_url(cred: &Credential, key: &str) -> Result<String> {
+    let (api_base, _, account_id, kv_id) = cred;
+
+    if matches!(key, "" | "." | "..") {
+        anyhow::bail!("{:?} cannot be used as a key in the URL of a value", key);
+    }
+
+    Ok(format!(
+        "{}/accounts/{}/storage/kv/namespaces/{}/values/{}",
+        api_base,
+        account_id,
+        kv_id,
+        utf8_percent_encode(key, NON_ALPHANUMERIC)
+    ))
+}
+
+/// Unparsed value of a key
+pub async fn get_key_raw(client: &Client, cred: &Credential, key: &str) -> Result<String> {
+    let (_, auth, _, _) = cred;
+    let url = value_url(cred, key)?;
+
+    let resp = match check_status(authorize(client.get(url), auth).send().await?).await {
+        Ok(resp) => resp,
+        Err(err) if is_not_found(&err) => {
+            return Err(err.context(KeyNotFound {
+                key: key.to_owned(),
+            }))
+        }
+        Err(err) => return Err(err),
+    };
+
+    Ok(resp.text().await?)
+}
+
+pub async fn get_key_value(
+    client: &Client,
+    cred: &Credential,
+    key: &str,
+    value_type: ValueType,
+) -> Result<u64> {
+    let raw = get_key_raw(client, cred, key).await?;
+
+    parse_views(key, &raw, value_type)
+}
+
+/// Smallest `expiration_ttl` Cloudflare accepts on writes
+pub const MIN_EXPIRATION_TTL: u64 = 60;
+
+/// Metadata and expiration attached to every written value
+#[derive(Debug, Clone, Default)]
+pub struct WriteMetadata {
+    pub metadata: Option<Value>,
+    pub expiration_ttl: Option<u64>,
+}
+
+impl WriteMetadata {
+    fn is_empty(&self) -> bool {
+        self.metadata.is_none() && self.expiration_ttl.is_none()
+    }
+
+    /// Point out the metadata or TTL when Cloudflare rejects a write carrying them
+    fn context(&self, err: anyhow::Error) -> anyhow::Error {
+        if self.is_empty() || err.downcast_ref::<CloudflareError>().is_none() {
+            return err;
+        }
+
+        err.context("Cloudflare rejected the write, check the metadata and expiration TTL")
+    }
+}
+
+/// Body of a write request
+#[derive(Debug, Clone)]
+enum WriteBody {
+    Text(String),
+    Json(Value),
+    Form { value: String, metadata: String },
+}
+
+/// Write request built without sending it, so dry runs can print exactly what would be sent
+#[derive(Debug, Clone)]
+pub struct WriteRequest {
+    method: Method,
+    url: String,
+    auth: Auth,
+    body: WriteBody,
+}
+
+impl WriteRequest {
+    pub async fn send(self, client: &Client) -> Result<()> {
+        let req = authorize(client.request(self.method, self.url), &self.auth);
+
+        let req = match self.body {
+            WriteBody::Text(value) => req.body(value),
+            WriteBody::Json(body) => req.json(&body),
+            WriteBody::Form { value, metadata } => {
+                req.multipart(Form::new().text("value", value).text("metadata", metadata))
+            }
+        };
+
+        read_envelope::<IgnoredAny>(req.send().await?).await?;
+
+        Ok(())
+    }
+}
+
+/// Method, URL, headers with redacted secrets and body, as in an HTTP request
+impl fmt::Display for WriteRequest {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "{} {}", self.method, self.url)?;
+
+        match &self.auth {
+            Auth::Token(_) => writeln!(f, "Authorization: Bearer <redacted>")?,
+            Auth::Key { email, .. } => {
+                writeln!(f, "X-Auth-Email: {}", email)?;
+                writeln!(f, "X-Auth-Key: <redacted>")?
+            }
+        }
+
+        match &self.body {
+            WriteBody::Text(value) => write!(f, "\n{}", value),
+            WriteBody::Json(body) => write!(
+                f,
+                "Content-Type: application/json\n\n{}",
+                serde_json::to_string_pretty(body).map_err(|_| fmt::Error)?
+            ),
+            WriteBody::Form { value, metadata } => write!(
+                f,
+                "Content-Type: multipart/form-data\n\nvalue: {}\nmetadata: {}",
+                value, metadata
+            ),
+        }
+    }
+}
+
+/// Request writing the value of a single key, which expires after `expiration_ttl` seconds if given
+pub fn put_key_value_request(
+    cred: &Credential,
+    key: &str,
+    value: String,
+    write_metadata: &WriteMetadata,
+) -> Result<WriteRequest> {
+    let (_, auth, _, _) = cred;
+    let mut url = value_url(cred, key)?;
+
+    if let Some(expiration_ttl) = write_metadata.expiration_ttl {
+        url = format!("{}?expiration_ttl={}", url, expiration_ttl);
+    }
+
+    let body = match &write_metadata.metadata {
+        Some(metadata) => WriteBody::Form {
+            value,
+            metadata: metadata.to_string(),
+        },
+        None => WriteBody::Text(value),
+    };
+
+    Ok(WriteRequest {
+        method: Method::PUT,
+        url,
+        auth: auth.clone(),
+        body,
+    })
+}
+
+pub async fn put_key_value(
+    client: &Client,
+    cred: &Credential,
+    key: &str,
+    value: String,
+    write_metadata: &WriteMetadata,
+) -> Result<()> {
+    put_key_value_request(cred, key, value, write_metadata)?
+        .send(client)
+        .await
+        .map_err(|err| write_metadata.context(err))
+}
+
+/// Maximum number of keys accepted by the bulk read endpoint
+pub const BULK_GET_LIMIT: usize = 100;
+
+async fn get_key_values_bulk(
+    client: &Client,
+    cred: &Credential,
+    keys: &[String],
+    value_type: ValueType,
+) -> Result<Vec<Result<(String, u64)>>> {
+    let (api_base, auth, account_id, kv_id) = cred;
+
+    let url = format!(
+        "{}/accounts/{}/storage/kv/namespaces/{}/bulk/get",
+        api_base, account_id, kv_id
+    );
+
+    // Other value types are read from the text of every value
+    let body = BulkGetBody {
+        keys,
+        value_type: if value_type == ValueType::Int {
+            "json"
+        } else {
+            "text"
+        },
+    };
+
+    let resp = authorize(client.post(url), auth).json(&body).send().await?;
+    let mut result = read_envelope::<BulkGetResult>(resp)
+        .await?
+        .result
+        .context("Cloudflare bulk read response is missing a result")?;
+
+    Ok(keys
+        .iter()
+        .map(|key| match result.values.remove(key) {
+            Some(Value::Null) | None => Err(anyhow::anyhow!("No value returned for key {}", key)),
+            Some(value) => match (value_type, &value) {
+                (ValueType::Int, _) => parse_json_views(&value),
+                (_, Value::String(text)) => value_type.views(text),
+                _ => None,
+            }
+            .map(|views| (key.clone(), views))
+            .ok_or_else(|| {
+                ValueParseError {
+                    key: key.clone(),
+                    raw: value.to_string(),
+                }
+                .into()
+            }),
+        })
+        .collect())
+}
+
+/// Shared state for fetching values during a download
+struct FetchContext {
+    client: Client,
+    credentials: Credential,
+    retry_policy: RetryPolicy,
+    timeout_per_key: Duration,
+    value_type: ValueType,
+    on_parse_error: ParseErrorPolicy,
+    namespace: Option<String>,
+    metadata_fields: Vec<String>,
+    decode_paths: bool,
+    /// Listed keys by name, only kept when metadata is included
+    listed_keys: HashMap<String, ListKey>,
+    tracker: Arc<RequestTracker>,
+}
+
+impl FetchContext {
+    fn record(&self, path: String, views: u64) -> CsvRecord {
+        let key = self.listed_keys.get(&path);
+
+        let metadata = self
+            .metadata_fields
+            .iter()
+            .filter_map(|field| {
+                key.and_then(|key| key.metadata.as_ref())
+                    .and_then(|metadata| metadata.get(field))
+                    .map(|value| (field.clone(), value.clone()))
+            })
+            .collect();
+        let path = if self.decode_paths {
+            decode_path(path)
+        } else {
+            path
+        };
+
+        CsvRecord {
+            namespace: self.namespace.clone(),
+            expiration: key.and_then(|key| key.expiration),
+            metadata,
+            path,
+            views,
+            rank: None,
+            percent: None,
+        }
+    }
+}
+
+/// Percent-decoded key, or the key as is when it does not decode to UTF-8
+fn decode_path(key: String) -> String {
+    match percent_decode_str(&key).decode_utf8() {
+        Ok(path) => path.into_owned(),
+        Err(err) => {
+            warn!("Keeping the path of {} as is: {}", key, err);
+
+            key
+        }
+    }
+}
+
+async fn fetch_value(ctx: &FetchContext, key: String) -> Result<(String, u64)> {
+    let fetch_res = with_retry(&ctx.retry_policy, || async {
+        ctx.tracker.wait(&ctx.tracker.value_requests).await?;
+
+        ctx.tracker
+            .timed(async {
+                tokio::time::timeout(
+                    ctx.timeout_per_key,
+                    get_key_value(&ctx.client, &ctx.credentials, &key, ctx.value_type),
+                )
+                .await
+                .unwrap_or_else(|_| {
+                    Err(KeyTimeoutError {
+                        timeout: ctx.timeout_per_key,
+                    }
+                    .into())
+                })
+            })
+            .await
+    })
+    .await
+    .map(|view| (key.clone(), view));
+
+    let (key, view) = zero_on_parse_error(ctx.on_parse_error, fetch_res)
+        .map_err(timeout_context)
+        .with_context(|| format!("Failed to fetch value of {}", key))?;
+
+    debug!("Fetched {} -> {}", &key, &view);
+
+    Ok((key, view))
+}
+
+async fn fetch_values_bulk(ctx: &FetchContext, keys: Vec<String>) -> Vec<Result<(String, u64)>> {
+    let bulk_res = with_retry(&ctx.retry_policy, || async {
+        ctx.tracker.wait(&ctx.tracker.value_requests).await?;
+
+        ctx.tracker
+            .timed(get_key_values_bulk(
+                &ctx.client,
+                &ctx.credentials,
+                &keys,
+                ctx.value_type,
+            ))
+            .await
+    })
+    .await;
+
+    match bulk_res {
+        Ok(values) => {
+            debug!("Fetched {} values in bulk", values.len());
+
+            values
+                .into_iter()
+                .map(|res| zero_on_parse_error(ctx.on_parse_error, res))
+                .collect()
+        }
+        Err(err) if matches!(response_error(&err), Some((StatusCode::NOT_FOUND, _))) => {
+            warn!("Bulk read endpoint not available, fetching values one by one");
+
+            let mut values = Vec::with_capacity(keys.len());
+
+            for key in keys {
+                values.push(fetch_value(ctx, key).await);
+            }
+
+            values
+        }
+        Err(err) => {
+            let message = err.to_string();
+
+            keys.into_iter()
+                .map(|key| Err(anyhow::anyhow!("Bulk fetch of {} failed: {}", key, message)))
+                .collect()
+        }
+    }
+}
+
+/// Paces requests with a token bucket shared by every namespace
+pub struct RateLimiter {
+    limiter: DefaultDirectRateLimiter,
+}
+
+impl RateLimiter {
+    pub fn per_second(requests: NonZeroU32) -> Self {
+        RateLimiter {
+            limiter: governor::RateLimiter::direct(Quota::per_second(requests)),
+        }
+    }
+
+    /// Wait until another request is allowed
+    pub async fn wait(&self) {
+        if self.limiter.check().is_err() {
+            debug!("Rate limit reached, delaying request");
+
+            self.limiter.until_ready().await;
+        }
+    }
+}
+
+/// Paces requests with an optional rate limit and counts them, including retries
+#[derive(Default)]
+pub struct RequestTracker {
+    limiter: Option<RateLimiter>,
+    list_requests: AtomicUsize,
+    value_requests: AtomicUsize,
+    /// Requests refused once this many were made
+    max_requests: Option<usize>,
+    /// List and value requests made or about to be, so concurrent ones never exceed the maximum
+    total_requests: AtomicUsize,
+    /// Set once a request was refused for going past the maximum
+    exhausted: AtomicBool,
+    /// Latency and outcome of every value request, only kept when benchmarking
+    samples: Option<Mutex<Vec<(Duration, bool)>>>,
+}
+
+impl RequestTracker {
+    pub fn new(rate_limit: Option<NonZeroU32>) -> Self {
+        RequestTracker {
+            limiter: rate_limit.map(RateLimiter::per_second),
+            ..RequestTracker::default()
+        }
+    }
+
+    /// Also keep the latency of every value request for `bench_report`
+    pub fn with_samples(mut self) -> Self {
+        self.samples = Some(Mutex::new(Vec::new()));
+        self
+    }
+
+    /// Refuse requests past a maximum number of list and value requests in total
+    pub fn with_max_requests(mut self, max_requests: Option<usize>) -> Self {
+        self.max_requests = max_requests;
+        self
+    }
+
+    /// Value request, timed when keeping samples
+    async fn timed<T>(&self, request: impl Future<Output = Result<T>>) -> Result<T> {
+        let samples = match &self.samples {
+            Some(samples) => samples,
+            None => return request.await,
+        };
+
+        let started = Instant::now();
+        let res = request.await;
+
+        // The request itself went fine when only the value was not a view count
+        let ok = res.as_ref().map_or_else(
+            |err| err.downcast_ref::<ValueParseError>().is_some(),
+            |_| true,
+        );
+
+        samples
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .push((started.elapsed(), ok));
+
+        res
+    }
+
+    /// Latency percentiles and errors of the value requests, if samples were kept
+    pub fn bench_report(&self) -> Option<BenchReport> {
+        let samples = self
+            .samples
+            .as_ref()?
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner);
+
+        let mut latencies = samples
+            .iter()
+            .map(|(latency, _)| *latency)
+            .collect::<Vec<_>>();
+        latencies.sort_unstable();
+
+        // Nearest rank, so every percentile is a latency that was measured
+        let percentile = |percent: usize| {
+            let rank = (latencies.len() * percent).div_ceil(100);
+
+            latencies
+                .get(rank.saturating_sub(1))
+                .copied()
+                .unwrap_or_default()
+        };
+
+        Some(BenchReport {
+            requests: samples.len(),
+            errors: samples.iter().filter(|(_, ok)| !ok).count(),
+            p50: percentile(50),
+            p95: percentile(95),
+            p99: percentile(99),
+        })
+    }
+
+    async fn wait(&self, requests: &AtomicUsize) -> Result<()> {
+        let max_requests = self.max_requests.unwrap_or(usize::MAX);
+
+        self.total_requests
+            .fetch_update(AtomicOrdering::Relaxed, AtomicOrdering::Relaxed, |total| {
+                (total < max_requests).then_some(total + 1)
+            })
+            .map_err(|total| {
+                self.exhausted.store(true, AtomicOrdering::Relaxed);
+
+                RequestBudgetError {
+                    max_requests,
+                    needed: total + 1,
+                }
+            })?;
+
+        if let Some(limiter) = &self.limiter {
+            limiter.wait().await;
+        }
+
+        requests.fetch_add(1, AtomicOrdering::Relaxed);
+
+        Ok(())
+    }
+
+    /// Whether a request was refused for going past the maximum
+    fn exhausted(&self) -> bool {
+        self.exhausted.load(AtomicOrdering::Relaxed)
+    }
+
+    /// Fail when the requests still needed on top of the ones made so far go past the maximum
+    fn check_budget(&self, needed: usize) -> Result<()> {
+        let made = self.total_requests.load(AtomicOrdering::Relaxed);
+
+        match self.max_requests {
+            Some(max_requests) if self.exhausted() || made + needed > max_requests => {
+                Err(RequestBudgetError {
+                    max_requests,
+                    needed: (made + needed).max(max_requests + 1),
+                }
+                .into())
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// Number of key listing requests so far
+    pub fn list_requests(&self) -> usize {
+        self.list_requests.load(AtomicOrdering::Relaxed)
+    }
+
+    /// Number of single and bulk value requests so far
+    pub fn value_requests(&self) -> usize {
+        self.value_requests.load(AtomicOrdering::Relaxed)
+    }
+}
+
+/// Value requests measured by `RequestTracker::with_samples`, counting every retry
+#[derive(Debug, Clone)]
+pub struct BenchReport {
+    pub requests: usize,
+    pub errors: usize,
+    pub p50: Duration,
+    pub p95: Duration,
+    pub p99: Duration,
+}
+
+/// Options of a download shared by every namespace
+#[derive(Debug, Clone)]
+pub struct DownloadOptions {
+    /// Maximum number of values fetched at the same time
+    pub concurrency: usize,
+    pub retry_policy: RetryPolicy,
+    /// Longest a single value request may take before it is retried, whatever the client timeout
+    pub timeout_per_key: Duration,
+    /// Fetch values in batches with the bulk read endpoint
+    pub bulk: bool,
+    /// Only download keys starting with this prefix
+    pub prefix: Option<String>,
+    /// Only download keys matching this regular expression
+    pub filter: Option<Regex>,
+    pub date_filter: Option<DateFilter>,
+    /// How the body of every value is read as a view count
+    pub value_type: ValueType,
+    pub on_parse_error: ParseErrorPolicy,
+    /// Key metadata fields added to the records
+    pub metadata_fields: Vec<String>,
+    /// Maximum number of requests per second across listing and fetching
+    pub rate_limit: Option<NonZeroU32>,
+    /// Only keep the first keys in listing order after filtering, across every namespace
+    pub limit: Option<usize>,
+    /// Stop fetching once more values than this failed after their retries
+    pub max_failures: Option<usize>,
+    /// Keys per listed page within `LIST_PAGE_SIZES`
+    pub list_page_size: Option<u32>,
+    /// List every namespace with concurrent listings of these prefixes instead of one page after
+    /// the other
+    pub parallel_listing: Option<Vec<String>>,
+    /// Cursor to continue a previous listing from, which only makes sense for one namespace
+    pub start_cursor: Option<String>,
+    /// Fetch exactly these keys in every namespace instead of listing them
+    pub keys: Option<Vec<String>>,
+    /// Fail instead of making more list and value requests than this in total, counting retries
+    pub max_total_requests: Option<usize>,
+    /// Percent-decode keys into the record paths, still fetching them by the key
+    pub decode_paths: bool,
+    /// Time every value request for `RequestTracker::bench_report`
+    pub bench: bool,
+    /// Only fetch this fraction of keys, always picking the same ones
+    pub sample: Option<f64>,
+}
+
+impl Default for DownloadOptions {
+    fn default() -> Self {
+        DownloadOptions {
+            concurrency: 16,
+            retry_policy: RetryPolicy {
+                max_retries: 3,
+                base_delay: Duration::from_millis(250),
+            },
+            timeout_per_key: Duration::from_secs(15),
+            bulk: false,
+            prefix: None,
+            filter: None,
+            date_filter: None,
+            value_type: ValueType::Int,
+            on_parse_error: ParseErrorPolicy::Fail,
+            metadata_fields: Vec::new(),
+            rate_limit: None,
+            limit: None,
+            max_failures: None,
+            list_page_size: None,
+            parallel_listing: None,
+            start_cursor: None,
+            keys: None,
+            max_total_requests: None,
+            decode_paths: false,
+            bench: false,
+            sample: None,
+        }
+    }
+}
+
+/// Values fetched for the listed keys, where failures are not in the records
+#[derive(Debug)]
+pub struct FetchedRecords {
+    pub records: Vec<CsvRecord>,
+    /// Records kept, which `Downloader::stream_records` writes instead of keeping in `records`
+    pub written: usize,
+    pub failures: usize,
+    pub total: usize,
+}
+
+/// Whether a key is in a sample of the given rate, picked by a hash that is the same on every run
+/// and platform
+fn is_sampled(key: &str, rate: f64) -> bool {
+    // 64-bit FNV-1a, whose high bits barely change between similar keys without the
+    // MurmurHash3 finalizer after it
+    let mut hash = key.bytes().fold(0xcbf2_9ce4_8422_2325_u64, |hash, byte| {
+        (hash ^ u64::from(byte)).wrapping_mul(0x0100_0000_01b3)
+    });
+
+    hash ^= hash >> 33;
+    hash = hash.wrapping_mul(0xff51_afd7_ed55_8ccd);
+    hash ^= hash >> 33;
+    hash = hash.wrapping_mul(0xc4ce_b9fe_1a85_ec53);
+    hash ^= hash >> 33;
+
+    (hash as f64 / u64::MAX as f64) < rate
+}
+
+/// Lists and fetches the keys of one or more namespaces
+pub struct Downloader {
+    options: DownloadOptions,
+    contexts: Vec<FetchContext>,
+    tracker: Arc<RequestTracker>,
+    /// Values of the current fetch that failed for reasons other than parsing
+    failures: AtomicUsize,
+    /// Set by Ctrl-C to stop starting new fetches
+    interrupted: Arc<AtomicBool>,
+    journal: Option<ResumeJournal>,
+}
+
+impl Downloader {
+    pub fn new(client: &Client, credentials: Vec<Credential>, options: DownloadOptions) -> Self {
+        let multiple_namespaces = credentials.len() > 1;
+        let tracker =
+            RequestTracker::new(options.rate_limit).with_max_requests(options.max_total_requests);
+        let tracker = Arc::new(if options.bench {
+            tracker.with_samples()
+        } else {
+            tracker
+        });
+        let contexts = credentials
+            .into_iter()
+            .map(|credentials| FetchContext {
+                client: client.clone(),
+                namespace: if multiple_namespaces {
+                    Some(credentials.3.clone())
+                } else {
+                    None
+                },
+                credentials,
+                retry_policy: options.retry_policy,
+                timeout_per_key: options.timeout_per_key,
+                value_type: options.value_type,
+                on_parse_error: options.on_parse_error,
+                metadata_fields: options.metadata_fields.clone(),
+                decode_paths: options.decode_paths,
+                listed_keys: HashMap::new(),
+                tracker: tracker.clone(),
+            })
+            .collect();
+
+        Downloader {
+            options,
+            contexts,
+            tracker,
+            failures: AtomicUsize::new(0),
+            interrupted: Arc::new(AtomicBool::new(false)),
+            journal: None,
+        }
+    }
+
+    /// Skip the values already in the journal at `path` and add every value fetched from now on
+    ///
+    /// The journal must have been written for the same namespaces and prefix, and a missing one
+    /// is created. Returns the number of values fetched before.
+    pub fn resume_from(&mut self, path: &Path) -> Result<usize> {
+        let header = JournalHeader {
+            account_id: self.contexts[0].credentials.2.clone(),
+            kv_ids: self
+                .contexts
+                .iter()
+                .map(|ctx| ctx.credentials.3.clone())
+                .collect(),
+            prefix: self.options.prefix.clone(),
+        };
+        let journal = ResumeJournal::open(path, &header)?;
+        let fetched = journal.fetched.len();
+
+        self.journal = Some(journal);
+
+        Ok(fetched)
+    }
+
+    /// Add a fetched value to the journal, where failing to only costs a refetch when resuming
+    fn journal_value(&self, ctx: &FetchContext, key: &str, views: u64) {
+        if let Some(journal) = &self.journal {
+            if let Err(err) = journal.append(&ctx.credentials.3, key, views) {
+                warn!("Could not add {} to the resume journal: {:#}", key, err);
+            }
+        }
+    }
+
+    /// Stop starting new fetches on Ctrl-C, so the values fetched so far can still be written
+    ///
+    /// A second Ctrl-C exits right away.
+    pub fn stop_on_ctrl_c(&self) {
+        let interrupted = self.interrupted.clone();
+
+        tokio::spawn(async move {
+            if tokio::signal::ctrl_c().await.is_ok() {
+                warn!(
+                    "Interrupted, waiting for the values being fetched, press Ctrl-C again to quit"
+                );
+
+                interrupted.store(true, AtomicOrdering::Relaxed);
+            }
+
+            if tokio::signal::ctrl_c().await.is_ok() {
+                std::process::exit(130);
+            }
+        });
+    }
+
+    /// Whether Ctrl-C stopped the fetch early
+    pub fn interrupted(&self) -> bool {
+        self.interrupted.load(AtomicOrdering::Relaxed)
+    }
+
+    /// Requests made by every namespace so far
+    pub fn requests(&self) -> &RequestTracker {
+        &self.tracker
+    }
+
+    pub fn options(&self) -> &DownloadOptions {
+        &self.options
+    }
+
+    /// List the keys of every namespace, or take the given keys, and apply the key filters
+    pub async fn list_keys(&mut self) -> Result<Vec<Vec<String>>> {
+        let mut namespace_keys = match &self.options.keys {
+            Some(keys) => {
+                if !self.options.metadata_fields.is_empty() {
+                    anyhow::bail!("key metadata is only known for listed keys, not given ones");
+                }
+
+                vec![keys.clone(); self.contexts.len()]
+            }
+            None => self.fetch_keys().await?,
+        };
+
+        let key_count =
+            |namespace_keys: &[Vec<String>]| -> usize { namespace_keys.iter().map(Vec::len).sum() };
+
+        info!("Found {} keys", key_count(&namespace_keys));
+
+        if let Some(filter) = &self.options.filter {
+            for keys in namespace_keys.iter_mut() {
+                keys.retain(|key| filter.is_match(key));
+            }
+
+            info!(
+                "Kept {} keys matching {}",
+                key_count(&namespace_keys),
+                filter
+            );
+        }
+
+        if let Some(date_filter) = &self.options.date_filter {
+            for keys in namespace_keys.iter_mut() {
+                keys.retain(|key| date_filter.matches(key));
+            }
+
+            info!(
+                "Kept {} keys within the date range",
+                key_count(&namespace_keys)
+            );
+        }
+
+        if let Some(rate) = self.options.sample {
+            for keys in namespace_keys.iter_mut() {
+                keys.retain(|key| is_sampled(key, rate));
+            }
+
+            info!(
+                "Sampled {} keys at a rate of {}",
+                key_count(&namespace_keys),
+                rate
+            );
+        }
+
+        if let Some(limit) = self.options.limit {
+            let mut remaining = limit;
+
+            for keys in namespace_keys.iter_mut() {
+                keys.truncate(remaining);
+                remaining -= keys.len();
+            }
+
+            info!("Kept the first {} keys", key_count(&namespace_keys));
+        }
+
+        Ok(namespace_keys)
+    }
+
+    /// List the key names of every namespace with the API, keeping their metadata when needed
+    async fn fetch_keys(&mut self) -> Result<Vec<Vec<String>>> {
+        info!("Fetching KV keys");
+
+        if self.options.start_cursor.is_some() && self.contexts.len() > 1 {
+            anyhow::bail!("a start cursor can only continue the listing of one KV namespace");
+        }
+
+        let list_options = ListOptions {
+            prefix: self.options.prefix.clone(),
+            // Listing can only stop early when no filter drops keys afterwards
+            limit: self.options.limit.filter(|_| {
+                self.options.filter.is_none()
+                    && self.options.date_filter.is_none()
+                    && self.options.sample.is_none()
+            }),
+            page_size: self.options.list_page_size,
+            start_cursor: self.options.start_cursor.clone(),
+        };
+
+        if self.options.parallel_listing.is_some() && self.options.start_cursor.is_some() {
+            anyhow::bail!("a start cursor can only continue a listing that is not parallel");
+        }
+
+        let list_options = &list_options;
+        let options = &self.options;
+        let listed = future::try_join_all(self.contexts.iter().map(|ctx| async move {
+            match &options.parallel_listing {
+                Some(prefixes) => {
+                    list_keys_parallel(
+                        &ctx.client,
+                        &ctx.credentials,
+                        list_options,
+                        prefixes,
+                        options.concurrency,
+                        Some(&ctx.tracker),
+                    )
+                    .await
+                }
+                None => {
+                    list_keys(
+                        &ctx.client,
+                        &ctx.credentials,
+                        list_options,
+                        Some(&ctx.tracker),
+                    )
+                    .await
+                }
+            }
+        }))
+        .await
+        .map_err(timeout_context)?;
+
+        let namespace_keys = self
+            .contexts
+            .iter_mut()
+            .zip(listed)
+            .map(|(ctx, keys)| {
+                let names = keys.iter().map(|key| key.name.clone()).collect::<Vec<_>>();
+
+                if !ctx.metadata_fields.is_empty() {
+                    ctx.listed_keys = keys
+                        .into_iter()
+                        .map(|key| (key.name.clone(), key))
+                        .collect();
+                }
+
+                names
+            })
+            .collect();
+
+        Ok(namespace_keys)
+    }
+
+    /// Values of the listed keys tagged with their listing position, in the order they arrive
+    fn fetch_stream<'a>(
+        &'a self,
+        namespace_keys: Vec<Vec<String>>,
+        progress: &'a ProgressBar,
+    ) -> LocalBoxStream<'a, (usize, Result<CsvRecord>)> {
+        info!("Fetching KV values");
+
+        progress.set_length(namespace_keys.iter().map(Vec::len).sum::<usize>() as u64);
+
+        self.failures.store(0, AtomicOrdering::Relaxed);
+
+        let namespace_keys = self.contexts.iter().zip(namespace_keys);
+
+        if self.options.bulk {
+            let mut batches = Vec::new();
+            let mut index = 0;
+
+            for (ctx, keys) in namespace_keys {
+                for batch in keys.chunks(BULK_GET_LIMIT) {
+                    batches.push((index, ctx, batch.to_vec()));
+
+                    index += batch.len();
+                }
+            }
+
+            stream::iter(batches)
+                .take_while(move |_| future::ready(!self.stopped()))
+                .map(move |(index, ctx, batch)| async move {
+                    fetch_values_bulk(ctx, batch)
+                        .await
+                        .into_iter()
+                        .enumerate()
+                        .map(|(offset, res)| {
+                            self.count_failure(&res);
+
+                            (
+                                index + offset,
+                                res.map(|(path, views)| {
+                                    self.journal_value(ctx, &path, views);
+
+                                    ctx.record(path, views)
+                                }),
+                            )
+                        })
+                        .collect::<Vec<_>>()
+                })
+                .buffer_unordered(self.options.concurrency)
+                .flat_map(stream::iter)
+                .inspect(move |_| progress.inc(1))
+                .boxed_local()
+        } else {
+            let keys = namespace_keys
+                .flat_map(|(ctx, keys)| keys.into_iter().map(move |key| (ctx, key)))
+                .enumerate()
+                .collect::<Vec<_>>();
+
+            stream::iter(keys)
+                .take_while(move |_| future::ready(!self.stopped()))
+                .map(move |(index, (ctx, key))| async move {
+                    let res = fetch_value(ctx, key).await;
+
+                    self.count_failure(&res);
+
+                    (
+                        index,
+                        res.map(|(path, views)| {
+                            self.journal_value(ctx, &path, views);
+
+                            ctx.record(path, views)
+                        }),
+                    )
+                })
+                .buffer_unordered(self.options.concurrency)
+                .inspect(move |_| progress.inc(1))
+                .boxed_local()
+        }
+    }
+
+    fn count_failure<T>(&self, res: &Result<T>) {
+        if let Err(err) = res {
+            if err.downcast_ref::<ValueParseError>().is_none() {
+                self.failures.fetch_add(1, AtomicOrdering::Relaxed);
+            }
+        }
+    }
+
+    /// Whether to stop starting new fetches
+    fn stopped(&self) -> bool {
+        self.tripped() || self.interrupted() || self.tracker.exhausted()
+    }
+
+    /// Fail before fetching when the values of the keys need more requests than allowed
+    fn check_request_budget(&self, namespace_keys: &[Vec<String>]) -> Result<()> {
+        let value_requests = namespace_keys
+            .iter()
+            .map(|keys| {
+                if self.options.bulk {
+                    keys.len().div_ceil(BULK_GET_LIMIT)
+                } else {
+                    keys.len()
+                }
+            })
+            .sum();
+
+        self.tracker.check_budget(value_requests)
+    }
+
+    /// Whether enough values failed to stop starting new fetches
+    fn tripped(&self) -> bool {
+        self.options
+            .max_failures
+            .is_some_and(|max_failures| self.failures.load(AtomicOrdering::Relaxed) > max_failures)
+    }
+
+    fn check_tripped(&self) -> Result<()> {
+        if self.tripped() {
+            return Err(CircuitBreakerError {
+                failures: self.failures.load(AtomicOrdering::Relaxed),
+            }
+            .into());
+        }
+
+        Ok(())
+    }
+
+    /// The fetched record, or nothing after logging why it was skipped or counting it as a failure
+    fn keep_record(&self, res: Result<CsvRecord>, failures: &mut usize) -> Option<CsvRecord> {
+        match res {
+            Ok(record) => Some(record),
+            Err(err)
+                if self.options.on_parse_error == ParseErrorPolicy::Skip
+                    && err.downcast_ref::<ValueParseError>().is_some() =>
+            {
+                warn!("{:#}, skipping", err);
+
+                None
+            }
+            Err(err) => {
+                warn!("{:#}", err);
+
+                *failures += 1;
+
+                None
+            }
+        }
+    }
+
+    /// Fetch the values of the listed keys, reporting every fetched value to the progress bar
+    pub async fn fetch_records(
+        &self,
+        namespace_keys: Vec<Vec<String>>,
+        progress: &ProgressBar,
+    ) -> Result<FetchedRecords> {
+        // Values fetched before keep their listing position, and `positions` has the listing
+        // position of every key left to fetch
+        let mut resumed = Vec::new();
+        let mut positions = Vec::new();
+        let namespace_keys = match &self.journal {
+            Some(journal) => {
+                let mut position = 0;
+                let mut left = Vec::new();
+
+                for (ctx, keys) in self.contexts.iter().zip(namespace_keys) {
+                    let mut namespace_left = Vec::new();
+
+                    for key in keys {
+                        match journal.views(&ctx.credentials.3, &key) {
+                            Some(views) => resumed.push((position, Ok(ctx.record(key, views)))),
+                            None => {
+                                positions.push(position);
+                                namespace_left.push(key);
+                            }
+                        }
+
+                        position += 1;
+                    }
+
+                    left.push(namespace_left);
+                }
+
+                info!("Resuming with {} values fetched before", resumed.len());
+
+                left
+            }
+            None => namespace_keys,
+        };
+
+        self.check_request_budget(&namespace_keys)?;
+
+        // Values arrive out of order, so each keeps its listing position
+        let mut data = self
+            .fetch_stream(namespace_keys, progress)
+            .collect::<Vec<_>>()
+            .await;
+
+        if self.journal.is_some() {
+            for (index, _) in &mut data {
+                *index = positions[*index];
+            }
+
+            data.extend(resumed);
+        }
+
+        progress.finish_and_clear();
+
+        self.check_tripped()?;
+        self.tracker.check_budget(0)?;
+
+        data.sort_unstable_by_key(|(index, _)| *index);
+
+        info!("Done fetching all value");
+
+        let total = data.len();
+        let mut failures = 0;
+        let records = data
+            .into_iter()
+            .filter_map(|(_, res)| self.keep_record(res, &mut failures))
+            .collect::<Vec<_>>();
+
+        Ok(FetchedRecords {
+            written: records.len(),
+            records,
+            failures,
+            total,
+        })
+    }
+
+    /// Write every value as a JSON line as soon as it is fetched, in no particular order
+    ///
+    /// Nothing is kept in memory, so the returned records are always empty.
+    pub async fn stream_records<W: Write>(
+        &self,
+        namespace_keys: Vec<Vec<String>>,
+        progress: &ProgressBar,
+        mut writer: W,
+        options: &WriteOptions,
+    ) -> Result<FetchedRecords> {
+        self.check_request_budget(&namespace_keys)?;
+
+        let mut values = self.fetch_stream(namespace_keys, progress);
+        let mut total = 0;
+        let mut written = 0;
+        let mut failures = 0;
+
+        while let Some((_, res)) = values.next().await {
+            total += 1;
+
+            if let Some(record) = self.keep_record(res, &mut failures) {
+                write_ndjson_record(&mut writer, &record, options)?;
+
+                written += 1;
+            }
+        }
+
+        progress.finish_and_clear();
+        writer.flush()?;
+
+        self.check_tripped()?;
+        self.tracker.check_budget(0)?;
+
+        info!("Done fetching all value");
+
+        Ok(FetchedRecords {
+            records: Vec::new(),
+            written,
+            failures,
+            total,
+        })
+    }
+}
+
+/// Download every value of a namespace, failing if any value could not be fetched
+pub async fn download(
+    client: &Client,
+    cred: &Credential,
+    options: DownloadOptions,
+) -> Result<Vec<CsvRecord>> {
+    let mut downloader = Downloader::new(client, vec![cred.clone()], options);
+    let namespace_keys = downloader.list_keys().await?;
+    let FetchedRecords {
+        records,
+        failures,
+        total,
+        ..
+    } = downloader
+        .fetch_records(namespace_keys, &ProgressBar::hidden())
+        .await?;
+
+    if failures > 0 {
+        return Err(FailedValuesError { failures, total }.into());
+    }
+
+    Ok(records)
+}
+
+/// Maximum number of pairs accepted by the bulk write endpoint
+pub const BULK_WRITE_LIMIT: usize = 10_000;
+
+/// Request writing the views of every record with the bulk write endpoint
+pub fn put_key_values_bulk_request(
+    cred: &Credential,
+    records: &[CsvRecord],
+    write_metadata: &WriteMetadata,
+) -> Result<WriteRequest> {
+    let (api_base, auth, account_id, kv_id) = cred;
+
+    let url = format!(
+        "{}/accounts/{}/storage/kv/namespaces/{}/bulk",
+        api_base, account_id, kv_id
+    );
+
+    let body = records
+        .iter()
+        .map(|record| BulkWritePair {
+            key: &record.path,
+            value: record.views.to_string(),
+            metadata: write_metadata.metadata.as_ref(),
+            expiration_ttl: write_metadata.expiration_ttl,
+        })
+        .collect::<Vec<_>>();
+
+    Ok(WriteRequest {
+        method: Method::PUT,
+        url,
+        auth: auth.clone(),
+        body: WriteBody::Json(serde_json::to_value(body)?),
+    })
+}
+
+pub async fn put_key_values_bulk(
+    client: &Client,
+    cred: &Credential,
+    records: &[CsvRecord],
+    write_metadata: &WriteMetadata,
+) -> Result<()> {
+    put_key_values_bulk_request(cred, records, write_metadata)?
+        .send(client)
+        .await
+        .map_err(|err| write_metadata.context(err))
+}
+
+/// Maximum number of keys accepted by the bulk delete endpoint
+pub const BULK_DELETE_LIMIT: usize = 10_000;
+
+/// Request deleting the keys with the bulk delete endpoint
+pub fn delete_keys_bulk_request(cred: &Credential, keys: &[String]) -> WriteRequest {
+    let (api_base, auth, account_id, kv_id) = cred;
+
+    let url = format!(
+        "{}/accounts/{}/storage/kv/namespaces/{}/bulk",
+        api_base, account_id, kv_id
+    );
+
+    WriteRequest {
+        method: Method::DELETE,
+        url,
+        auth: auth.clone(),
+        body: WriteBody::Json(json!(keys)),
+    }
+}
+
+pub async fn delete_keys_bulk(client: &Client, cred: &Credential, keys: &[String]) -> Result<()> {
+    delete_keys_bulk_request(cred, keys).send(client).await
+}
+
+/// Record of an appended snapshot, where the time comes first
+#[derive(Serialize)]
+struct SnapshotRecord<'a> {
+    snapshot_at: String,
+    #[serde(flatten)]
+    record: JsonRecord<'a>,
+}
+
+/// Record as a JSON object with only the chosen columns, in their order, when there are any
+struct JsonRecord<'a> {
+    record: &'a CsvRecord,
+    columns: Option<&'a [Column]>,
+}
+
+impl Serialize for JsonRecord<'_> {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        use serde::ser::SerializeMap;
+
+        let columns = match self.columns {
+            Some(columns) => columns,
+            None => return self.record.serialize(serializer),
+        };
+
+        let mut map = serializer.serialize_map(None)?;
+
+        for column in columns {
+            map.serialize_entry(column.name(), &column.value(self.record))?;
+        }
+
+        if !self.record.metadata.is_empty() {
+            map.serialize_entry("metadata", &self.record.metadata)?;
+        }
+
+        map.end()
+    }
+}
+
+fn write_ndjson_record<W: Write>(
+    mut writer: W,
+    record: &CsvRecord,
+    options: &WriteOptions,
+) -> Result<()> {
+    let record = JsonRecord {
+        record,
+        columns: options.columns.as_deref(),
+    };
+
+    match options.snapshot_at {
+        Some(snapshot_at) => serde_json::to_writer(
+            &mut writer,
+            &SnapshotRecord {
+                snapshot_at: snapshot_at.to_rfc3339(),
+                record,
+            },
+        )?,
+        None => serde_json::to_writer(&mut writer, &record)?,
+    }
+
+    writeln!(writer)?;
+
+    Ok(())
+}
+
+/// Version of the `DownloadOutput` JSON, raised whenever a field changes or goes away
+pub const SCHEMA_VERSION: u32 = 1;
+
+/// Run that wrote an output, as described by JSON output
+#[derive(Debug, Clone, Serialize)]
+pub struct OutputSource {
+    pub generated_at: DateTime<Utc>,
+    pub account_id: String,
+    pub kv_ids: Vec<String>,
+}
+
+#[derive(Debug, Serialize)]
+struct OutputTotals {
+    pages: usize,
+    views: u64,
+}
+
+/// JSON output of a download, with the run and totals next to the records
+#[derive(Serialize)]
+struct DownloadOutput<'a> {
+    schema_version: u32,
+    #[serde(flatten)]
+    source: &'a OutputSource,
+    totals: OutputTotals,
+    records: Vec<JsonRecord<'a>>,
+}
+
+/// Options of the written output, where the CSV ones are ignored by other formats
+#[derive(Debug, Clone)]
+pub struct WriteOptions {
+    /// Key metadata fields added as columns
+    pub metadata_fields: Vec<String>,
+    /// Field delimiter of CSV output, where TSV output always uses a tab
+    pub delimiter: u8,
+    /// Write the header row of CSV and TSV output
+    pub headers: bool,
+    /// Start CSV and TSV output with a UTF-8 byte order mark, which Excel needs to read UTF-8
+    pub bom: bool,
+    /// Time added as a leading `snapshot_at` column or field when appending
+    pub snapshot_at: Option<DateTime<Utc>>,
+    /// Longest path in table output before it is cut with an ellipsis
+    pub max_path_width: Option<usize>,
+    /// Only these fields in this order in CSV, TSV and JSON output, followed by any metadata
+    pub columns: Option<Vec<Column>>,
+    /// Decimals of the percentages in CSV, TSV and table output
+    pub precision: usize,
+    /// Run wrapped around the records of JSON output, which is a bare array of records without it
+    pub source: Option<OutputSource>,
+}
+
+impl Default for WriteOptions {
+    fn default() -> Self {
+        WriteOptions {
+            metadata_fields: Vec::new(),
+            delimiter: b',',
+            headers: true,
+            bom: false,
+            snapshot_at: None,
+            max_path_width: Some(60),
+            columns: None,
+            precision: 2,
+            source: None,
+        }
+    }
+}
+
+/// Byte order mark Excel looks for to read a CSV file as UTF-8
+const UTF8_BOM: &[u8] = b"\xEF\xBB\xBF";
+
+pub fn write_records<W: Write>(
+    mut writer: W,
+    format: OutputFormat,
+    records: &[CsvRecord],
+    options: &WriteOptions,
+) -> Result<()> {
+    match format {
+        OutputFormat::Csv | OutputFormat::Tsv => {
+            let delimiter = if format == OutputFormat::Tsv {
+                b'\t'
+            } else {
+                options.delimiter
+            };
+
+            if options.bom {
+                writer.write_all(UTF8_BOM)?;
+            }
+
+            let mut wtr = WriterBuilder::new()
+                .delimiter(delimiter)
+                .has_headers(options.headers)
+                .from_writer(writer);
+
+            let with_stats = records.iter().any(|record| record.rank.is_some());
+
+            if options.metadata_fields.is_empty()
+                && options.snapshot_at.is_none()
+                && options.columns.is_none()
+                && !with_stats
+            {
+                for record in records {
+                    wtr.serialize(record)?;
+                }
+
+                wtr.flush()?;
+            } else {
+                write_csv_columns(wtr, records, options)?;
+            }
+        }
+        OutputFormat::Json => {
+            let json_records = records
+                .iter()
+                .map(|record| JsonRecord {
+                    record,
+                    columns: options.columns.as_deref(),
+                })
+                .collect::<Vec<_>>();
+
+            match &options.source {
+                Some(source) => serde_json::to_writer(
+                    &mut writer,
+                    &DownloadOutput {
+                        schema_version: SCHEMA_VERSION,
+                        source,
+                        totals: OutputTotals {
+                            pages: records.len(),
+                            views: total_views(records),
+                        },
+                        records: json_records,
+                    },
+                )?,
+                None => serde_json::to_writer(&mut writer, &json_records)?,
+            }
+
+            writeln!(writer)?;
+            writer.flush()?;
+        }
+        OutputFormat::Ndjson => {
+            for record in records {
+                write_ndjson_record(&mut writer, record, options)?;
+            }
+
+            writer.flush()?;
+        }
+        OutputFormat::Prometheus => {
+            write_prometheus(writer, records)?;
+        }
+        OutputFormat::Sqlite => anyhow::bail!("SQLite output is written with write_sqlite"),
+        #[cfg(feature = "xlsx")]
+        OutputFormat::Xlsx => {
+            write_xlsx(writer, records, options)?;
+        }
+        #[cfg(not(feature = "xlsx"))]
+        OutputFormat::Xlsx => anyhow::bail!("xlsx output needs a build with the xlsx feature"),
+        OutputFormat::Table => {
+            write_table(writer, records, options)?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Sheet of pages sorted by views with a bold header and a total row,
+/// keeping counts as numeric cells
+#[cfg(feature = "xlsx")]
+fn write_xlsx<W: Write>(
+    mut writer: W,
+    records: &[CsvRecord],
+    options: &WriteOptions,
+) -> Result<()> {
+    use rust_xlsxwriter::{Format, Workbook};
+
+    let mut records = records.iter().collect::<Vec<_>>();
+    records.sort_by(|a, b| b.views.cmp(&a.views).then_with(|| a.path.cmp(&b.path)));
+
+    let namespaced = records.iter().any(|record| record.namespace.is_some());
+    let bold = Format::new().set_bold();
+
+    let mut headers = Vec::new();
+    if namespaced {
+        headers.push("namespace".to_owned());
+    }
+    headers.push("path".to_owned());
+    headers.push("views".to_owned());
+    headers.extend(
+        options
+            .metadata_fields
+            .iter()
+            .map(|field| format!("metadata.{}", field)),
+    );
+
+    let mut workbook = Workbook::new();
+    let sheet = workbook.add_worksheet();
+
+    for (col, header) in headers.iter().enumerate() {
+        sheet.write_string_with_format(0, col as u16, header, &bold)?;
+    }
+
+    let views_col = if namespaced { 1 } else { 0 } + 1;
+
+    for (index, record) in records.iter().enumerate() {
+        let row = index as u32 + 1;
+        let mut col = 0;
+
+        if namespaced {
+            sheet.write_string(row, col, record.namespace.as_deref().unwrap_or_default())?;
+            col += 1;
+        }
+
+        sheet.write_string(row, col, &record.path)?;
+        sheet.write_number(row, views_col, record.views as f64)?;
+        col = views_col + 1;
+
+        for field in &options.metadata_fields {
+            match record.metadata.get(field) {
+                Some(Value::Number(number)) => {
+                    sheet.write_number(row, col, number.as_f64().unwrap_or_default())?;
+                }
+                value => {
+                    sheet.write_string(row, col, metadata_text(value))?;
+                }
+            }
+            col += 1;
+        }
+    }
+
+    let total_row = records.len() as u32 + 1;
+    let total = records
+        .iter()
+        .fold(0u64, |total, record| total.saturating_add(record.views));
+
+    sheet.write_string_with_format(total_row, views_col - 1, "Total", &bold)?;
+    sheet.write_number_with_format(total_row, views_col, total as f64, &bold)?;
+    sheet.set_freeze_panes(1, 0)?;
+    sheet.autofit();
+
+    writer.write_all(&workbook.save_to_buffer()?)?;
+    writer.flush()?;
+
+    Ok(())
+}
+
+fn write_table<W: Write>(
+    mut writer: W,
+    records: &[CsvRecord],
+    options: &WriteOptions,
+) -> Result<()> {
+    let label = |record: &CsvRecord| {
+        let path = match options.max_path_width {
+            Some(width) if record.path.chars().count() > width => {
+                let kept = record.path.chars().take(width.saturating_sub(1));
+
+                kept.chain(Some('…')).collect()
+            }
+            _ => record.path.clone(),
+        };
+
+        match &record.namespace {
+            Some(namespace) => format!("{}:{}", namespace, path),
+            None => path,
+        }
+    };
+    let labels = records.iter().map(label).collect::<Vec<_>>();
+    let width = labels
+        .iter()
+        .map(|label| label.chars().count())
+        .chain(Some("path".len()))
+        .max()
+        .unwrap_or_default();
+
+    if options.headers {
+        writeln!(writer, "{:<width$}  {:>10}", "path", "views", width = width)?;
+    }
+
+    for (label, record) in labels.iter().zip(records) {
+        writeln!(
+            writer,
+            "{:<width$}  {:>10}",
+            label,
+            record.views,
+            width = width
+        )?;
+    }
+
+    writer.flush()?;
+
+    Ok(())
+}
+
+/// Metadata field as a cell, leaving strings unquoted
+fn metadata_text(value: Option<&Value>) -> String {
+    match value {
+        Some(Value::String(text)) => text.clone(),
+        Some(Value::Null) | None => String::new(),
+        Some(value) => value.to_string(),
+    }
+}
+
+/// Escape a label value as required by the Prometheus exposition format
+fn escape_label_value(value: &str) -> String {
+    let mut escaped = String::with_capacity(value.len());
+
+    for ch in value.chars() {
+        match ch {
+            '\\' => escaped.push_str("\\\\"),
+            '"' => escaped.push_str("\\\""),
+            '\n' => escaped.push_str("\\n"),
+            ch => escaped.push(ch),
+        }
+    }
+
+    escaped
+}
+
+/// Gauges of every page along with the total views and number of keys
+fn write_prometheus<W: Write>(mut writer: W, records: &[CsvRecord]) -> Result<()> {
+    let total_views = total_views(records);
+
+    writeln!(writer, "# HELP page_tracker_views Views of a tracked page")?;
+    writeln!(writer, "# TYPE page_tracker_views gauge")?;
+
+    for record in records {
+        let namespace = record
+            .namespace
+            .as_ref()
+            .map(|namespace| format!("namespace=\"{}\",", escape_label_value(namespace)))
+            .unwrap_or_default();
+
+        writeln!(
+            writer,
+            "page_tracker_views{{{}path=\"{}\"}} {}",
+            namespace,
+            escape_label_value(&record.path),
+            record.views
+        )?;
+    }
+
+    writeln!(
+        writer,
+        "# HELP page_tracker_total Total views of all tracked pages"
+    )?;
+    writeln!(writer, "# TYPE page_tracker_total gauge")?;
+    writeln!(writer, "page_tracker_total {}", total_views)?;
+    writeln!(writer, "# HELP page_tracker_keys Number of tracked pages")?;
+    writeln!(writer, "# TYPE page_tracker_keys gauge")?;
+    writeln!(writer, "page_tracker_keys {}", records.len())?;
+
+    writer.flush()?;
+
+    Ok(())
+}
+
+/// Text safe to put in HTML and SVG elements and attributes
+fn escape_html(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+
+    for c in text.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&#39;"),
+            c => escaped.push(c),
+        }
+    }
+
+    escaped
+}
+
+/// Sorts the table by the clicked header, numerically for views
+const REPORT_SCRIPT: &str = r#"
+document.querySelectorAll("th").forEach(function (th, column) {
+  th.addEventListener("click", function () {
+    var body = th.closest("table").tBodies[0];
+    var numeric = th.dataset.numeric === "true";
+    var descending = th.dataset.order !== "desc";
+    th.dataset.order = descending ? "desc" : "asc";
+    Array.from(body.rows)
+      .sort(function (a, b) {
+        var x = a.cells[column].dataset.value || a.cells[column].textContent;
+        var y = b.cells[column].dataset.value || b.cells[column].textContent;
+        var order = numeric ? x - y : x.localeCompare(y);
+        return descending ? -order : order;
+      })
+      .forEach(function (row) { body.appendChild(row); });
+  });
+});
+"#;
+
+const REPORT_STYLE: &str = r#"
+body { font-family: sans-serif; margin: 2em; color: #222; }
+table { border-collapse: collapse; }
+th, td { padding: 0.3em 0.8em; border-bottom: 1px solid #ddd; text-align: left; }
+th { cursor: pointer; user-select: none; }
+td.views { text-align: right; font-variant-numeric: tabular-nums; }
+svg text { font-size: 12px; }
+"#;
+
+/// Self-contained HTML page with the total views, a bar chart of the `top` pages and a table
+/// of every page sortable by clicking its headers, with shares of the views to `precision` decimals
+pub fn write_html_report<W: Write>(
+    mut writer: W,
+    records: &[CsvRecord],
+    generated_at: DateTime<Utc>,
+    top: usize,
+    precision: usize,
+) -> Result<()> {
+    let total_views = total_views(records);
+    let namespaced = records.iter().any(|record| record.namespace.is_some());
+
+    let mut top_records = most_viewed(records, top);
+    top_records.sort_by(|a, b| b.views.cmp(&a.views).then_with(|| a.path.cmp(&b.path)));
+
+    writeln!(writer, "<!DOCTYPE html>")?;
+    writeln!(writer, "<html lang=\"en\">")?;
+    writeln!(writer, "<head>")?;
+    writeln!(writer, "<meta charset=\"utf-8\">")?;
+    writeln!(writer, "<title>Page views</title>")?;
+    writeln!(writer, "<style>{}</style>", REPORT_STYLE)?;
+    writeln!(writer, "</head>")?;
+    writeln!(writer, "<body>")?;
+    writeln!(writer, "<h1>Page views</h1>")?;
+    writeln!(
+        writer,
+        "<p>{} views of {} pages, generated at {}</p>",
+        total_views,
+        records.len(),
+        generated_at.format("%F %T UTC")
+    )?;
+
+    // Bars scale to the most viewed page, with the path and views written next to them
+    let max_views = top_records.first().map_or(0, |record| record.views).max(1);
+    let bar_height = 20;
+    let bar_width = 400.0;
+
+    writeln!(writer, "<h2>Top {} pages</h2>", top_records.len())?;
+    writeln!(
+        writer,
+        "<svg width=\"900\" height=\"{}\" role=\"img\">",
+        top_records.len() * (bar_height + 4)
+    )?;
+
+    for (index, record) in top_records.iter().enumerate() {
+        let y = index * (bar_height + 4);
+        let width = record.views as f64 / max_views as f64 * bar_width;
+
+        writeln!(
+            writer,
+            "<rect x=\"0\" y=\"{}\" width=\"{:.1}\" height=\"{}\" fill=\"#4a7bd0\"></rect>",
+            y, width, bar_height
+        )?;
+        writeln!(
+            writer,
+            "<text x=\"{:.1}\" y=\"{}\">{} {}</text>",
+            width + 6.0,
+            y + bar_height - 6,
+            record.views,
+            escape_html(&record.path)
+        )?;
+    }
+
+    writeln!(writer, "</svg>")?;
+    writeln!(writer, "<h2>All pages</h2>")?;
+    writeln!(writer, "<table>")?;
+    write!(writer, "<thead><tr>")?;
+
+    if namespaced {
+        write!(writer, "<th>Namespace</th>")?;
+    }
+
+    writeln!(
+        writer,
+        "<th>Path</th><th data-numeric=\"true\">Views</th><th data-numeric=\"true\">Share</th></tr></thead>"
+    )?;
+    writeln!(writer, "<tbody>")?;
+
+    for record in records {
+        write!(writer, "<tr>")?;
+
+        if namespaced {
+            write!(
+                writer,
+                "<td>{}</td>",
+                escape_html(record.namespace.as_deref().unwrap_or_default())
+            )?;
+        }
+
+        let share = if total_views == 0 {
+            0.0
+        } else {
+            round_to(record.views as f64 * 100.0 / total_views as f64, precision)
+        };
+
+        writeln!(
+            writer,
+            "<td>{}</td><td class=\"views\" data-value=\"{}\">{}</td><td class=\"views\" data-value=\"{}\">{:.*}%</td></tr>",
+            escape_html(&record.path),
+            record.views,
+            record.views,
+            share,
+            precision,
+            share
+        )?;
+    }
+
+    writeln!(writer, "</tbody>")?;
+    writeln!(writer, "</table>")?;
+    writeln!(writer, "<script>{}</script>", REPORT_SCRIPT)?;
+    writeln!(writer, "</body>")?;
+    writeln!(writer, "</html>")?;
+
+    writer.flush()?;
+
+    Ok(())
+}
+
+/// CSV rows with the snapshot time, expiration and chosen metadata fields flattened into columns
+fn write_csv_columns<W: Write>(
+    mut wtr: Writer<W>,
+    records: &[CsvRecord],
+    options: &WriteOptions,
+) -> Result<()> {
+    let snapshot_at = options
+        .snapshot_at
+        .map(|snapshot_at| snapshot_at.to_rfc3339());
+    let with_metadata = !options.metadata_fields.is_empty();
+
+    let mut default_columns = Vec::new();
+
+    if records.iter().any(|record| record.namespace.is_some()) {
+        default_columns.push(Column::Namespace);
+    }
+
+    default_columns.push(Column::Path);
+    default_columns.push(Column::Views);
+
+    if records.iter().any(|record| record.rank.is_some()) {
+        default_columns.push(Column::Rank);
+        default_columns.push(Column::Percent);
+    }
+
+    if with_metadata {
+        default_columns.push(Column::Expiration);
+    }
+
+    let columns = options.columns.as_deref().unwrap_or(&default_columns);
+
+    let mut header = Vec::new();
+
+    if snapshot_at.is_some() {
+        header.push("snapshot_at".to_owned());
+    }
+
+    header.extend(columns.iter().map(|column| column.name().to_owned()));
+
+    if with_metadata {
+        header.extend(
+            options
+                .metadata_fields
+                .iter()
+                .map(|field| format!("metadata.{}", field)),
+        );
+    }
+
+    if options.headers {
+        wtr.write_record(&header)?;
+    }
+
+    for record in records {
+        let mut row = Vec::with_capacity(header.len());
+
+        if let Some(snapshot_at) = &snapshot_at {
+            row.push(snapshot_at.clone());
+        }
+
+        row.extend(
+            columns
+                .iter()
+                .map(|column| column.text(record, options.precision)),
+        );
+
+        if with_metadata {
+            row.extend(
+                options
+                    .metadata_fields
+                    .iter()
+                    .map(|field| metadata_text(record.metadata.get(field))),
+            );
+        }
+
+        wtr.write_record(&row)?;
+    }
+
+    wtr.flush()?;
+
+    Ok(())
+}
+
+/// Append a snapshot to the `views` table of a SQLite database, keeping the KV id of every page
+pub fn write_sqlite(path: &Path, snapshot_at: DateTime<Utc>, records: &[CsvRecord]) -> Result<()> {
+    let mut conn = Connection::open(path)?;
+
+    conn.execute_batch(
+        "CREATE TABLE IF NOT EXISTS views (
+            snapshot_at TEXT NOT NULL,
+            namespace TEXT,
+            path TEXT NOT NULL,
+            views INTEGER NOT NULL
+        );",
+    )?;
+
+    // Databases written before namespaces were kept lack the column and its place in the index
+    let has_namespace = conn
+        .prepare("SELECT 1 FROM pragma_table_info('views') WHERE name = 'namespace'")?
+        .exists([])?;
+
+    if !has_namespace {
+        conn.execute_batch(
+            "ALTER TABLE views ADD COLUMN namespace TEXT;
+            DROP INDEX IF EXISTS views_path_snapshot_at;",
+        )?;
+    }
+
+    conn.execute_batch(
+        "CREATE INDEX IF NOT EXISTS views_path_snapshot_at ON views (namespace, path, snapshot_at);",
+    )?;
+
+    let tx = conn.transaction()?;
+    let snapshot_at = snapshot_at.to_rfc3339();
+
+    {
+        let mut stmt = tx.prepare(
+            "INSERT INTO views (snapshot_at, namespace, path, views) VALUES (?1, ?2, ?3, ?4)",
+        )?;
+
+        for record in records {
+            let views = i64::try_from(record.views)
+                .with_context(|| format!("Views of {} do not fit in SQLite", record.path))?;
+
+            stmt.execute(params![snapshot_at, record.namespace, record.path, views])?;
+        }
+    }
+
+    tx.commit()?;
+
+    Ok(())
+}
+
+/// Upsert a snapshot into the `page_views` table of a PostgreSQL database in one transaction
+///
+/// Rows are keyed by the snapshot time, account, namespace and path, so writing the same snapshot
+/// again only updates its views. TLS is used whenever the server and `sslmode` allow it.
+#[cfg(feature = "postgres")]
+pub async fn write_postgres(
+    config: &str,
+    source: &OutputSource,
+    records: &[CsvRecord],
+) -> Result<()> {
+    let connector = postgres_native_tls::MakeTlsConnector::new(native_tls::TlsConnector::new()?);
+    let (mut client, connection) = tokio_postgres::connect(config, connector)
+        .await
+        .context("Could not connect to PostgreSQL")?;
+
+    tokio::spawn(async move {
+        if let Err(err) = connection.await {
+            warn!("PostgreSQL connection failed: {}", err);
+        }
+    });
+
+    client
+        .batch_execute(
+            "CREATE TABLE IF NOT EXISTS page_views (
+                snapshot_at TIMESTAMPTZ NOT NULL,
+                account_id TEXT NOT NULL,
+                kv_id TEXT NOT NULL,
+                path TEXT NOT NULL,
+                views BIGINT NOT NULL,
+                PRIMARY KEY (snapshot_at, account_id, kv_id, path)
+            )",
+        )
+        .await?;
+
+    let tx = client.transaction().await?;
+    let stmt = tx
+        .prepare(
+            "INSERT INTO page_views (snapshot_at, account_id, kv_id, path, views)
+            VALUES ($1, $2, $3, $4, $5)
+            ON CONFLICT (snapshot_at, account_id, kv_id, path) DO UPDATE SET views = EXCLUDED.views",
+        )
+        .await?;
+
+    for record in records {
+        let kv_id = match &record.namespace {
+            Some(kv_id) => kv_id,
+            None => source
+                .kv_ids
+                .first()
+                .context("Records without a namespace need a KV id")?,
+        };
+        let views = i64::try_from(record.views)
+            .with_context(|| format!("Views of {} do not fit in PostgreSQL", record.path))?;
+
+        tx.execute(
+            &stmt,
+            &[
+                &source.generated_at,
+                &source.account_id,
+                kv_id,
+                &record.path,
+                &views,
+            ],
+        )
+        .await?;
+    }
+
+    tx.commit().await?;
+
+    Ok(())
+}
+
+#[cfg(not(feature = "postgres"))]
+pub async fn write_postgres(
+    _config: &str,
+    _source: &OutputSource,
+    _records: &[CsvRecord],
+) -> Result<()> {
+    anyhow::bail!("--postgres needs a build with the postgres feature")
+}
+
+/// Path without trailing slashes, keeping the root as `/`
+fn normalize_path(path: &str, lowercase: bool) -> String {
+    let path = match path.trim_end_matches('/') {
+        "" if path.starts_with('/') => "/",
+        trimmed => trimmed,
+    };
+
+    if lowercase {
+        path.to_lowercase()
+    } else {
+        path.to_owned()
+    }
+}
+
+/// Merge records whose paths only differ by trailing slashes, or case with `lowercase`, summing their views
+///
+/// Merged records keep the position and key metadata of the first one.
+pub fn normalize_records(records: Vec<CsvRecord>, lowercase: bool) -> Vec<CsvRecord> {
+    merge_records(records, |path| normalize_path(path, lowercase))
+}
+
+/// Leading segments of a path without its query string, fragment or trailing slash
+fn section_path(path: &str, depth: usize) -> String {
+    let path = path.split(['?', '#']).next().unwrap_or_default();
+    let segments = path
+        .split('/')
+        .filter(|segment| !segment.is_empty())
+        .take(depth)
+        .collect::<Vec<_>>();
+
+    format!("/{}", segments.join("/"))
+}
+
+/// Sum the views of every path within its first `depth` segments, so `/blog/a` and `/blog/b/` become `/blog`
+///
+/// Sections keep the position of their first page but no key metadata.
+pub fn group_records(records: Vec<CsvRecord>, depth: usize) -> Vec<CsvRecord> {
+    let mut sections = merge_records(records, |path| section_path(path, depth));
+
+    for section in &mut sections {
+        section.expiration = None;
+        section.metadata.clear();
+    }
+
+    sections
+}
+
+/// Merge records of each namespace whose paths `merged_path` maps to the same path
+fn merge_records(records: Vec<CsvRecord>, merged_path: impl Fn(&str) -> String) -> Vec<CsvRecord> {
+    let mut positions: HashMap<(Option<String>, String), usize> = HashMap::new();
+    let mut merged: Vec<CsvRecord> = Vec::with_capacity(records.len());
+
+    for mut record in records {
+        let path = merged_path(&record.path);
+        let id = (record.namespace.clone(), path.clone());
+
+        match positions.get(&id) {
+            Some(&position) => {
+                let target = &mut merged[position];
+
+                debug!(
+                    "Merged {} into {} -> {}",
+                    record.path,
+                    target.path,
+                    target.views.saturating_add(record.views)
+                );
+
+                target.views = target.views.saturating_add(record.views);
+            }
+            None => {
+                if path != record.path {
+                    debug!("Mapped {} to {}", record.path, path);
+                }
+
+                record.path = path;
+                positions.insert(id, merged.len());
+                merged.push(record);
+            }
+        }
+    }
+
+    merged
+}
+
+/// Set the rank by views and the percentage of the total views of every record, rounded to
+/// `precision` decimals
+pub fn add_stats(records: &mut [CsvRecord], precision: usize) {
+    let total = total_views(records);
+
+    let mut views = records
+        .iter()
+        .map(|record| record.views)
+        .collect::<Vec<_>>();
+    views.sort_unstable_by(|a, b| b.cmp(a));
+
+    for record in records.iter_mut() {
+        // Ties share the rank of the first of them
+        let rank = views.partition_point(|views| *views > record.views) + 1;
+        let percent = if total == 0 {
+            0.0
+        } else {
+            record.views as f64 * 100.0 / total as f64
+        };
+
+        record.rank = Some(rank);
+        record.percent = Some(round_to(percent, precision));
+    }
+}
+
+/// Stable sort of the records, keeping the listing order for `SortKey::None`
+pub fn sort_records(records: &mut [CsvRecord], sort: SortKey, order: SortOrder) {
+    let compare = |a: &CsvRecord, b: &CsvRecord| match sort {
+        SortKey::Key => (&a.namespace, &a.path).cmp(&(&b.namespace, &b.path)),
+        SortKey::Views => a.views.cmp(&b.views),
+        SortKey::None => Ordering::Equal,
+    };
+
+    match order {
+        SortOrder::Asc => records.sort_by(compare),
+        SortOrder::Desc => records.sort_by(|a, b| compare(b, a)),
+    }
+}
+
+/// Sum of the views, saturating instead of overflowing
+pub fn total_views(records: &[CsvRecord]) -> u64 {
+    records
+        .iter()
+        .fold(0u64, |total, record| total.saturating_add(record.views))
+}
+
+/// Signed change between two view counts, saturating at the bounds of `i64`
+pub fn views_change(before: u64, after: u64) -> i64 {
+    let change = i128::from(after) - i128::from(before);
+
+    i64::try_from(change).unwrap_or(if change < 0 { i64::MIN } else { i64::MAX })
+}
+
+/// The `top` most viewed records, kept in their current order
+pub fn most_viewed(records: &[CsvRecord], top: usize) -> Vec<CsvRecord> {
+    let mut ranked = (0..records.len()).collect::<Vec<_>>();
+    ranked.sort_by(|&a, &b| records[b].views.cmp(&records[a].views).then(a.cmp(&b)));
+    ranked.truncate(top);
+    ranked.sort_unstable();
+
+    ranked
+        .into_iter()
+        .map(|index| records[index].clone())
+        .collect()
+}
+
+/// Filters of a download kept in its manifest
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct ManifestFilters {
+    pub prefix: Option<String>,
+    pub filter: Option<String>,
+    pub since: Option<NaiveDate>,
+    pub until: Option<NaiveDate>,
+    pub limit: Option<usize>,
+    pub sample: Option<f64>,
+}
+
+impl ManifestFilters {
+    pub fn new(options: &DownloadOptions) -> Self {
+        let date_filter = options.date_filter.as_ref();
+
+        ManifestFilters {
+            prefix: options.prefix.clone(),
+            filter: options
+                .filter
+                .as_ref()
+                .map(|filter| filter.as_str().to_owned()),
+            since: date_filter.and_then(|date_filter| date_filter.since),
+            until: date_filter.and_then(|date_filter| date_filter.until),
+            limit: options.limit,
+            sample: options.sample,
+        }
+    }
+}
+
+/// Sidecar JSON describing the run that wrote a snapshot
+#[derive(Debug, Clone, Serialize)]
+pub struct Manifest {
+    pub created_at: DateTime<Utc>,
+    pub account_id: String,
+    pub kv_ids: Vec<String>,
+    pub key_count: usize,
+    /// Unknown when the records were streamed to the output
+    pub total_views: Option<u64>,
+    pub failures: usize,
+    pub version: String,
+    pub filters: ManifestFilters,
+}
+
+/// `<output>.meta.json` next to the output file
+pub fn manifest_path(output: &Path) -> PathBuf {
+    let mut path = output.as_os_str().to_owned();
+    path.push(".meta.json");
+
+    PathBuf::from(path)
+}
+
+pub fn write_manifest(output: &Path, manifest: &Manifest) -> Result<()> {
+    let path = manifest_path(output);
+
+    info!("Writing the manifest to {}", path.display());
+
+    let mut writer = BufWriter::new(File::create(&path)?);
+
+    serde_json::to_writer_pretty(&mut writer, manifest)?;
+    writeln!(writer)?;
+    writer.flush()?;
+
+    Ok(())
+}
+
+/// Bucket and object key written by `--output-s3`, from `s3://bucket/prefix/%FT%TZ.csv`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct S3Location {
+    pub bucket: String,
+    /// Object key, which is a file name format until the output is written
+    pub key: String,
+}
+
+impl FromStr for S3Location {
+    type Err = anyhow::Error;
+
+    fn from_str(value: &str) -> Result<Self> {
+        let (bucket, key) = value
+            .strip_prefix("s3://")
+            .and_then(|location| location.split_once('/'))
+            .context("S3 locations look like s3://bucket/key")?;
+
+        if bucket.is_empty() || key.is_empty() {
+            anyhow::bail!("{} needs both a bucket and a key", value);
+        }
+
+        Ok(S3Location {
+            bucket: bucket.to_owned(),
+            key: key.to_owned(),
+        })
+    }
+}
+
+impl fmt::Display for S3Location {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "s3://{}/{}", self.bucket, self.key)
+    }
+}
+
+/// How long the signed upload URL of `put_s3_object` stays valid
+#[cfg(feature = "s3")]
+const S3_SIGNATURE_EXPIRY: Duration = Duration::from_secs(15 * 60);
+
+/// Upload `body` to S3, or to any S3-compatible storage at `endpoint`
+///
+/// Credentials come from `AWS_ACCESS_KEY_ID`, `AWS_SECRET_ACCESS_KEY` and the optional
+/// `AWS_SESSION_TOKEN`, and the region from `AWS_REGION` or `AWS_DEFAULT_REGION`. A custom
+/// endpoint is addressed with path-style URLs, which is what most compatible servers expect.
+#[cfg(feature = "s3")]
+pub async fn put_s3_object(
+    client: &Client,
+    location: &S3Location,
+    endpoint: Option<&str>,
+    content_type: &str,
+    body: Vec<u8>,
+) -> Result<()> {
+    use rusty_s3::{Bucket, Credentials, S3Action, UrlStyle};
+    use std::env;
+
+    let credentials = Credentials::from_env()
+        .context("Uploading to S3 needs AWS_ACCESS_KEY_ID and AWS_SECRET_ACCESS_KEY")?;
+    let region = env::var("AWS_REGION")
+        .or_else(|_| env::var("AWS_DEFAULT_REGION"))
+        .unwrap_or_else(|_| "us-east-1".to_owned());
+    let (endpoint, url_style) = match endpoint {
+        Some(endpoint) => (endpoint.to_owned(), UrlStyle::Path),
+        None => (
+            format!("https://s3.{}.amazonaws.com", region),
+            UrlStyle::VirtualHost,
+        ),
+    };
+    let endpoint = endpoint
+        .parse()
+        .with_context(|| format!("Invalid S3 endpoint {}", endpoint))?;
+    let bucket = Bucket::new(endpoint, url_style, location.bucket.clone(), region)
+        .with_context(|| format!("Invalid S3 bucket {}", location.bucket))?;
+    let url = bucket
+        .put_object(Some(&credentials), &location.key)
+        .sign(S3_SIGNATURE_EXPIRY);
+
+    info!("Uploading {} bytes to {}", body.len(), location);
+
+    let response = client
+        .put(url.as_str())
+        .header(reqwest::header::CONTENT_TYPE, content_type)
+        .body(body)
+        .send()
+        .await
+        .with_context(|| format!("Failed to upload to {}", location))?;
+    let status = response.status();
+
+    if !status.is_success() {
+        let text = response.text().await.unwrap_or_default();
+
+        anyhow::bail!(
+            "S3 refused the upload to {} with {}: {}",
+            location,
+            status,
+            text
+        );
+    }
+
+    Ok(())
+}
+
+#[cfg(not(feature = "s3"))]
+pub async fn put_s3_object(
+    _client: &Client,
+    _location: &S3Location,
+    _endpoint: Option<&str>,
+    _content_type: &str,
+    _body: Vec<u8>,
+) -> Result<()> {
+    anyhow::bail!("--output-s3 needs a build with the s3 feature")
+}
+
+/// Keys of a file with one key per line, skipping blank lines and repeated keys
+pub fn read_keys_file(path: &Path) -> Result<Vec<String>> {
+    let text = fs::read_to_string(path)
+        .with_context(|| format!("Could not read the keys file {}", path.display()))?;
+    let mut seen = BTreeSet::new();
+    let keys = text
+        .lines()
+        .filter(|key| !key.is_empty() && seen.insert(*key))
+        .map(str::to_owned)
+        .collect::<Vec<_>>();
+
+    if keys.is_empty() {
+        anyhow::bail!("The keys file {} has no keys", path.display());
+    }
+
+    info!("Read {} keys from {}", keys.len(), path.display());
+
+    Ok(keys)
+}
+
+/// Keys already downloaded by earlier runs, by KV id
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct SeenKeys(BTreeMap<String, BTreeSet<String>>);
+
+impl SeenKeys {
+    /// Keys of the state file, where a missing file has seen no keys
+    pub fn read(path: &Path) -> Result<Self> {
+        if !path.exists() {
+            info!("No key state at {}, so every key is new", path.display());
+
+            return Ok(SeenKeys::default());
+        }
+
+        serde_json::from_reader(BufReader::new(File::open(path)?))
+            .with_context(|| format!("Could not read the key state {}", path.display()))
+    }
+
+    pub fn write(&self, path: &Path) -> Result<()> {
+        let mut writer = BufWriter::new(File::create(path)?);
+
+        serde_json::to_writer(&mut writer, self)?;
+        writer.flush()?;
+
+        Ok(())
+    }
+
+    /// Drop the keys of the namespace seen before
+    pub fn retain_new(&self, kv_id: &str, keys: &mut Vec<String>) {
+        if let Some(seen) = self.0.get(kv_id) {
+            keys.retain(|key| !seen.contains(key));
+        }
+    }
+
+    pub fn insert(&mut self, kv_id: &str, keys: &[String]) {
+        self.0
+            .entry(kv_id.to_owned())
+            .or_default()
+            .extend(keys.iter().cloned());
+    }
+}
+
+/// First line of a resume journal, telling which listing its values belong to
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+struct JournalHeader {
+    account_id: String,
+    kv_ids: Vec<String>,
+    prefix: Option<String>,
+}
+
+/// Value fetched by an earlier run, one JSON line each after the header
+#[derive(Debug, Serialize, Deserialize)]
+struct JournalEntry {
+    kv_id: String,
+    key: String,
+    views: u64,
+}
+
+/// File every fetched value is added to, so an interrupted download can resume without
+/// fetching them again
+#[derive(Debug)]
+pub struct ResumeJournal {
+    file: File,
+    /// Views fetched by earlier runs, by KV id and key
+    fetched: HashMap<(String, String), u64>,
+}
+
+impl ResumeJournal {
+    /// Values of the journal at `path`, created when missing, which must have been written for
+    /// the same namespaces and prefix
+    fn open(path: &Path, header: &JournalHeader) -> Result<Self> {
+        let content = match fs::read_to_string(path) {
+            Ok(content) => content,
+            Err(err) if err.kind() == ErrorKind::NotFound => String::new(),
+            Err(err) => return Err(err.into()),
+        };
+        let mut fetched = HashMap::new();
+        let mut lines = content.lines();
+
+        if let Some(line) = lines.next() {
+            let found = serde_json::from_str::<JournalHeader>(line)
+                .with_context(|| format!("{} is not a resume journal", path.display()))?;
+
+            if &found != header {
+                anyhow::bail!(
+                    "{} resumes a download of other namespaces or another prefix, remove it to start over",
+                    path.display()
+                );
+            }
+        }
+
+        // A run that crashed can leave its last line cut short
+        let complete = content.is_empty() || content.ends_with('\n');
+        let lines = lines.collect::<Vec<_>>();
+
+        for (index, line) in lines.iter().enumerate() {
+            match serde_json::from_str::<JournalEntry>(line) {
+                Ok(entry) => {
+                    fetched.insert((entry.kv_id, entry.key), entry.views);
+                }
+                Err(err) if !complete && index + 1 == lines.len() => {
+                    warn!(
+                        "Ignoring the unfinished last line of {}: {}",
+                        path.display(),
+                        err
+                    );
+                }
+                Err(err) => {
+                    return Err(err).with_context(|| {
+                        format!("Line {} of {} is not a value", index + 2, path.display())
+                    })
+                }
+            }
+        }
+
+        let mut file = OpenOptions::new().create(true).append(true).open(path)?;
+
+        if content.is_empty() {
+            writeln!(file, "{}", serde_json::to_string(header)?)?;
+        } else if !complete {
+            // Drop the unfinished line so the next values start on a line of their own
+            let end = content.rfind('\n').map_or(0, |index| index + 1);
+
+            file.set_len(end as u64)?;
+        }
+
+        Ok(ResumeJournal { file, fetched })
+    }
+
+    fn views(&self, kv_id: &str, key: &str) -> Option<u64> {
+        self.fetched
+            .get(&(kv_id.to_owned(), key.to_owned()))
+            .copied()
+    }
+
+    /// Add a fetched value, written at once so a crash loses at most the line being written
+    fn append(&self, kv_id: &str, key: &str, views: u64) -> Result<()> {
+        let mut line = serde_json::to_string(&JournalEntry {
+            kv_id: kv_id.to_owned(),
+            key: key.to_owned(),
+            views,
+        })?;
+
+        line.push('\n');
+
+        (&self.file).write_all(line.as_bytes())?;
+
+        Ok(())
+    }
+}
+
+/// One record per path of every namespace across the snapshots, in the order paths are first seen
+pub fn merge_snapshots(snapshots: Vec<Vec<CsvRecord>>, strategy: MergeStrategy) -> Vec<CsvRecord> {
+    let mut positions: HashMap<(Option<String>, String), usize> = HashMap::new();
+    let mut merged: Vec<CsvRecord> = Vec::new();
+
+    for record in snapshots.into_iter().flatten() {
+        let id = (record.namespace.clone(), record.path.clone());
+
+        match positions.get(&id) {
+            Some(&position) => {
+                let target = &mut merged[position];
+
+                target.views = match strategy {
+                    MergeStrategy::Sum => target.views.saturating_add(record.views),
+                    MergeStrategy::Max => target.views.max(record.views),
+                    MergeStrategy::Last => record.views,
+                };
+            }
+            None => {
+                positions.insert(id, merged.len());
+                merged.push(record);
+            }
+        }
+    }
+
+    merged
+}
+
+/// Records of a CSV file, where repeated paths keep the position of their first row
+pub fn read_csv_records(path: &Path, on_duplicate: DuplicatePolicy) -> Result<Vec<CsvRecord>> {
+    let mut positions: HashMap<(Option<String>, String), usize> = HashMap::new();
+    let mut records: Vec<CsvRecord> = Vec::new();
+
+    for record in Reader::from_path(path)?.deserialize::<CsvRecord>() {
+        let record = record?;
+        let id = (record.namespace.clone(), record.path.clone());
+
+        let position = match positions.get(&id) {
+            Some(&position) => position,
+            None => {
+                positions.insert(id, records.len());
+                records.push(record);
+
+                continue;
+            }
+        };
+        let target = &mut records[position];
+
+        match on_duplicate {
+            DuplicatePolicy::Error => anyhow::bail!(
+                "{} has more than one row for {}, pick how to read them with --on-duplicate",
+                path.display(),
+                record.path
+            ),
+            DuplicatePolicy::Last => *target = record,
+            DuplicatePolicy::Sum => target.views = target.views.saturating_add(record.views),
+        }
+    }
+
+    Ok(records)
+}
+
+/// Changed, added and removed pages sorted by the largest increase first, where pages of
+/// different namespaces are told apart
+pub fn diff_records(from: &[CsvRecord], to: &[CsvRecord]) -> Vec<DiffRecord> {
+    let page = |record: &CsvRecord| (record.namespace.clone(), record.path.clone());
+
+    let from_views = from
+        .iter()
+        .map(|record| (page(record), record.views))
+        .collect::<HashMap<_, _>>();
+    let to_views = to
+        .iter()
+        .map(|record| (page(record), record.views))
+        .collect::<HashMap<_, _>>();
+
+    let mut diffs = to
+        .iter()
+        .filter_map(|record| {
+            let before = from_views.get(&page(record)).copied();
+            let change = views_change(before.unwrap_or(0), record.views);
+
+            let status = match before {
+                None => DiffStatus::Added,
+                Some(_) if change > 0 => DiffStatus::Increased,
+                Some(_) if change < 0 => DiffStatus::Decreased,
+                Some(_) => return None,
+            };
+
+            Some(DiffRecord {
+                namespace: record.namespace.clone(),
+                path: record.path.clone(),
+                status,
+                from: before,
+                to: Some(record.views),
+                change,
+            })
+        })
+        .collect::<Vec<_>>();
+
+    diffs.extend(
+        from.iter()
+            .filter(|record| !to_views.contains_key(&page(record)))
+            .map(|record| DiffRecord {
+                namespace: record.namespace.clone(),
+                path: record.path.clone(),
+                status: DiffStatus::Removed,
+                from: Some(record.views),
+                to: None,
+                change: views_change(record.views, 0),
+            }),
+    );
+
+    diffs.sort_by(|a, b| {
+        b.change
+            .cmp(&a.change)
+            .then_with(|| a.namespace.cmp(&b.namespace))
+            .then_with(|| a.path.cmp(&b.path))
+    });
+
+    diffs
+}
+
+pub fn write_diff<W: Write>(mut writer: W, format: DiffFormat, diffs: &[DiffRecord]) -> Result<()> {
+    let namespaced = diffs.iter().any(|diff| diff.namespace.is_some());
+
+    match format {
+        DiffFormat::Csv if namespaced => {
+            // Written by hand, since serializing leaves the column out of pages without one
+            let mut wtr = Writer::from_writer(writer);
+            let optional = |views: Option<u64>| views.map(|v| v.to_string()).unwrap_or_default();
+
+            wtr.write_record(["namespace", "path", "status", "from", "to", "change"])?;
+
+            for diff in diffs {
+                wtr.write_record([
+                    diff.namespace.clone().unwrap_or_default(),
+                    diff.path.clone(),
+                    diff.status.to_string(),
+                    optional(diff.from),
+                    optional(diff.to),
+                    diff.change.to_string(),
+                ])?;
+            }
+
+            wtr.flush()?;
+        }
+        DiffFormat::Csv => {
+            let mut wtr = Writer::from_writer(writer);
+
+            for diff in diffs {
+                wtr.serialize(diff)?;
+            }
+
+            wtr.flush()?;
+        }
+        DiffFormat::Json => {
+            serde_json::to_writer(&mut writer, diffs)?;
+            writeln!(writer)?;
+            writer.flush()?;
+        }
+        DiffFormat::Table => {
+            let optional =
+                |views: Option<u64>| views.map_or_else(|| "-".to_owned(), |v| v.to_string());
+            let width = diffs
+                .iter()
+                .map(|diff| diff.path.len())
+                .chain(Some("path".len()))
+                .max()
+                .unwrap_or_default();
+            let namespace_width = diffs
+                .iter()
+                .filter_map(|diff| diff.namespace.as_ref().map(String::len))
+                .chain(Some("namespace".len()))
+                .max()
+                .unwrap_or_default();
+
+            if namespaced {
+                write!(writer, "{:<width$}  ", "namespace", width = namespace_width)?;
+            }
+
+            writeln!(
+                writer,
+                "{:<width$}  {:<9}  {:>10}  {:>10}  {:>10}",
+                "path",
+                "status",
+                "from",
+                "to",
+                "change",
+                width = width
+            )?;
+
+            for diff in diffs {
+                if namespaced {
+                    write!(
+                        writer,
+                        "{:<width$}  ",
+                        diff.namespace.as_deref().unwrap_or("-"),
+                        width = namespace_width
+                    )?;
+                }
+
+                writeln!(
+                    writer,
+                    "{:<width$}  {:<9}  {:>10}  {:>10}  {:>+10}",
+                    diff.path,
+                    diff.status.to_string(),
+                    optional(diff.from),
+                    optional(diff.to),
+                    diff.change,
+                    width = width
+                )?;
+            }
+
+            writer.flush()?;
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Records of an in-memory CSV snapshot
+    fn records(csv: &str) -> Vec<CsvRecord> {
+        Reader::from_reader(csv.as_bytes())
+            .deserialize()
+            .collect::<Result<_, _>>()
+            .unwrap()
+    }
+
+    fn diff_csv(diffs: &[DiffRecord]) -> String {
+        let mut output = Vec::new();
+
+        write_diff(&mut output, DiffFormat::Csv, diffs).unwrap();
+
+        String::from_utf8(output).unwrap()
+    }
+
+    #[test]
+    fn diffs_two_snapshots_by_the_largest_increase() {
+        let from = records("path,views\n/,10\n/about,5\n/old,3\n/same,7\n");
+        let to = records("path,views\n/,12\n/about,1\n/new,20\n/same,7\n");
+
+        assert_eq!(
+            diff_csv(&diff_records(&from, &to)),
+            "path,status,from,to,change\n\
+             /new,added,,20,20\n\
+             /,increased,10,12,2\n\
+             /old,removed,3,,-3\n\
+             /about,decreased,5,1,-4\n"
+        );
+    }
+
+    #[test]
+    fn diffs_the_same_path_of_several_namespaces_apart() {
+        let from = records("namespace,path,views\na,/x,1\nb,/x,100\n");
+        let to = records("namespace,path,views\na,/x,5\nb,/x,100\n");
+
+        assert_eq!(
+            diff_csv(&diff_records(&from, &to)),
+            "namespace,path,status,from,to,change\na,/x,increased,1,5,4\n"
+        );
+    }
+
+    #[test]
+    fn parses_views_of_numbers_and_quoted_numbers() {
+        for raw in ["42", "\"42\"", "42.0"] {
+            assert_eq!(
+                parse_views("/", raw, ValueType::Int).unwrap(),
+                42,
+                "{}",
+                raw
+            );
+        }
+    }
+
+    #[test]
+    fn refuses_views_of_an_object_unless_recorded_as_zero() {
+        let err = parse_views("/", "{}", ValueType::Int).unwrap_err();
+        let parse_err = err.downcast_ref::<ValueParseError>().unwrap();
+
+        assert_eq!(
+            (parse_err.key.as_str(), parse_err.raw.as_str()),
+            ("/", "{}")
+        );
+
+        let res = parse_views("/", "{}", ValueType::Int).map(|views| ("/".to_owned(), views));
+
+        assert_eq!(
+            zero_on_parse_error(ParseErrorPolicy::Zero, res).unwrap(),
+            ("/".to_owned(), 0)
+        );
+    }
+
+    #[test]
+    fn escapes_quotes_backslashes_and_newlines_of_prometheus_labels() {
+        let records = records("path,views\n\"/say \"\"hi\"\"\\now\n\",3\n");
+        let mut output = Vec::new();
+
+        write_prometheus(&mut output, &records).unwrap();
+
+        assert_eq!(records[0].path, "/say \"hi\"\\now\n");
+        assert!(String::from_utf8(output)
+            .unwrap()
+            .contains(r#"page_tracker_views{path="/say \"hi\"\\now\n"} 3"#));
+    }
+
+    #[test]
+    fn reads_views_past_u32_max_and_saturates_their_total() {
+        let past_u32 = u64::from(u32::MAX) + 1;
+
+        assert_eq!(
+            parse_views("/", &past_u32.to_string(), ValueType::Int).unwrap(),
+            past_u32
+        );
+
+        let records = records(&format!(
+            "path,views\n/,{}\n/about,{}\n/blog,{}\n",
+            u32::MAX,
+            past_u32,
+            u64::MAX
+        ));
+
+        assert_eq!(records[0].views, u64::from(u32::MAX));
+        assert_eq!(total_views(&records[..2]), u64::from(u32::MAX) * 2 + 1);
+        assert_eq!(total_views(&records), u64::MAX);
+    }
+
+    /// Fails to build if the request futures stop being `Send`, which spawning them on tokio needs
+    #[test]
+    fn request_futures_are_send() {
+        fn assert_send<T: Send>(_: T) {}
+
+        let client = Client::new();
+        let cred: Credential = (
+            "http://localhost".to_owned(),
+            Auth::Token("jwt".to_owned()),
+            "account_id".to_owned(),
+            "kv_id".to_owned(),
+        );
+        let tracker = RequestTracker::new(None);
+
+        assert_send(list_keys(
+            &client,
+            &cred,
+            &ListOptions::default(),
+            Some(&tracker),
+        ));
+        assert_send(get_key_value(&client, &cred, "", ValueType::Int));
+    }
+
+    #[test]
+    fn merges_three_snapshots_with_overlapping_paths() {
+        let snapshots = || {
+            vec![
+                records("path,views\n/,1\n/about,5\n"),
+                records("path,views\n/about,2\n/blog,3\n"),
+                records("path,views\n/,4\n/blog,1\n/docs,7\n"),
+            ]
+        };
+        let views = |records: Vec<CsvRecord>| {
+            records
+                .into_iter()
+                .map(|record| (record.path, record.views))
+                .collect::<Vec<_>>()
+        };
+        let expected = |views: [u64; 4]| {
+            ["/", "/about", "/blog", "/docs"]
+                .iter()
+                .map(|path| path.to_string())
+                .zip(views)
+                .collect::<Vec<_>>()
+        };
+
+        assert_eq!(
+            views(merge_snapshots(snapshots(), MergeStrategy::Sum)),
+            expected([5, 7, 4, 7])
+        );
+        assert_eq!(
+            views(merge_snapshots(snapshots(), MergeStrategy::Max)),
+            expected([4, 5, 3, 7])
+        );
+        assert_eq!(
+            views(merge_snapshots(snapshots(), MergeStrategy::Last)),
+            expected([4, 2, 1, 7])
+        );
+    }
+
+    #[tokio::test]
+    async fn retries_a_refused_connection_as_a_connect_failure() {
+        // A port that was just free is closed once its listener is dropped
+        let port = std::net::TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap()
+            .port();
+        let err = Client::new()
+            .get(format!("http://127.0.0.1:{}/", port))
+            .send()
+            .await
+            .unwrap_err();
+        let err = anyhow::Error::from(err);
+
+        assert_eq!(failure_category(&err), Some(FailureCategory::Connect));
+        assert_eq!(retry_hint(&err), Some((FailureCategory::Connect, None)));
+    }
+
+    #[test]
+    fn adds_the_namespace_to_sqlite_databases_without_one() {
+        let path =
+            std::env::temp_dir().join(format!("page-tracker-sqlite-{}.db", std::process::id()));
+        let _ = fs::remove_file(&path);
+
+        Connection::open(&path)
+            .unwrap()
+            .execute_batch(
+                "CREATE TABLE views (
+                    snapshot_at TEXT NOT NULL,
+                    path TEXT NOT NULL,
+                    views INTEGER NOT NULL
+                );
+                CREATE INDEX views_path_snapshot_at ON views (path, snapshot_at);
+                INSERT INTO views VALUES ('2024-01-01T00:00:00+00:00', '/x', 1);",
+            )
+            .unwrap();
+
+        write_sqlite(
+            &path,
+            Utc::now(),
+            &records("namespace,path,views\na,/x,5\nb,/x,100\n"),
+        )
+        .unwrap();
+
+        let conn = Connection::open(&path).unwrap();
+        let rows = conn
+            .prepare("SELECT namespace, path, views FROM views ORDER BY views")
+            .unwrap()
+            .query_map([], |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?)))
+            .unwrap()
+            .collect::<Result<Vec<(Option<String>, String, i64)>, _>>()
+            .unwrap();
+        let index_columns = conn
+            .prepare("SELECT name FROM pragma_index_info('views_path_snapshot_at')")
+            .unwrap()
+            .query_map([], |row| row.get(0))
+            .unwrap()
+            .collect::<Result<Vec<String>, _>>()
+            .unwrap();
+
+        fs::remove_file(&path).unwrap();
+
+        assert_eq!(
+            rows,
+            [
+                (None, "/x".to_owned(), 1),
+                (Some("a".to_owned()), "/x".to_owned(), 5),
+                (Some("b".to_owned()), "/x".to_owned(), 100),
+            ]
+        );
+        assert_eq!(index_columns, ["namespace", "path", "snapshot_at"]);
+    }
+}
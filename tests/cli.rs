@@ -0,0 +1,307 @@
+mod common;
+
+use common::{mount_keys, mount_value, NAMESPACE};
+use flate2::read::GzDecoder;
+use serde_json::{json, Value};
+use std::{
+    env, fs,
+    io::Read,
+    path::PathBuf,
+    process::{self, Command},
+    time::Duration,
+};
+use wiremock::{
+    matchers::{body_json, method, path},
+    Mock, MockServer, ResponseTemplate,
+};
+
+/// Command of the built binary against the mock API, without the
+/// credentials and config files of whoever runs the tests, where `args`
+/// ends with the subcommand
+fn page_tracker(server: &MockServer, home: &PathBuf, args: &[&str]) -> Command {
+    let mut command = Command::new(env!("CARGO_BIN_EXE_page-tracker"));
+
+    command
+        .env("HOME", home)
+        .env("PT_API_BASE", server.uri())
+        .env("PT_JWT", "jwt")
+        .env("PT_ACCOUNT_ID", "account_id")
+        .env("NO_COLOR", "1")
+        .env_remove("PT_KV_ID")
+        .env_remove("PT_CONFIG")
+        .env_remove("PT_PROFILE")
+        .args(args)
+        .args(["--kv-id", "kv_id"]);
+
+    command
+}
+
+/// Empty directory of a test, left behind for a look after failures
+fn temp_dir(name: &str) -> PathBuf {
+    let dir = env::temp_dir().join(format!("page-tracker-{}-{}", name, process::id()));
+
+    let _ = fs::remove_dir_all(&dir);
+    fs::create_dir_all(&dir).unwrap();
+
+    dir
+}
+
+#[tokio::test]
+async fn writes_a_templated_file_of_a_single_namespace() {
+    let server = MockServer::start().await;
+    let dir = temp_dir("single-namespace");
+
+    mount_keys(&server, &["/", "/about"]).await;
+    mount_value(&server, "%2F", "42").await;
+    mount_value(&server, "%2Fabout", "7").await;
+
+    let output = page_tracker(&server, &dir, &["download"])
+        .arg("--output-dir")
+        .arg(&dir)
+        .args(["--output-format", "views-{kv}.csv"])
+        .output()
+        .unwrap();
+
+    assert!(output.status.success(), "{:?}", output);
+    assert_eq!(
+        fs::read_to_string(dir.join("views-kv_id.csv")).unwrap(),
+        "path,views\n/,42\n/about,7\n"
+    );
+}
+
+#[tokio::test]
+async fn lists_the_keys_of_a_global_dry_run() {
+    let server = MockServer::start().await;
+    let dir = temp_dir("dry-run-list");
+
+    mount_keys(&server, &["/", "/about"]).await;
+
+    let output = page_tracker(&server, &dir, &["--dry-run", "download"])
+        .arg("--list")
+        .output()
+        .unwrap();
+
+    assert!(output.status.success(), "{:?}", output);
+    assert_eq!(String::from_utf8(output.stdout).unwrap(), "/\n/about\n");
+
+    let output = page_tracker(&server, &dir, &["download"])
+        .arg("--list")
+        .output()
+        .unwrap();
+
+    assert_eq!(output.status.code(), Some(1));
+    assert!(String::from_utf8(output.stderr)
+        .unwrap()
+        .contains("--list only prints the keys of --dry-run"));
+}
+
+#[tokio::test]
+async fn fails_a_stream_of_only_skipped_values_when_asked_to() {
+    let server = MockServer::start().await;
+    let dir = temp_dir("stream-skipped");
+
+    mount_keys(&server, &["/", "/about"]).await;
+    mount_value(&server, "%2F", "\"many\"").await;
+    mount_value(&server, "%2Fabout", "{}").await;
+
+    let output = page_tracker(&server, &dir, &["download"])
+        .args(["--stream", "--format", "ndjson", "--output"])
+        .arg(dir.join("views.ndjson"))
+        .args(["--on-parse-error", "skip", "--fail-on-empty"])
+        .output()
+        .unwrap();
+
+    assert_eq!(output.status.code(), Some(1), "{:?}", output);
+    assert!(String::from_utf8(output.stderr)
+        .unwrap()
+        .contains("Fetched no values out of 2 keys"));
+}
+
+#[tokio::test]
+async fn writes_csv_that_decompresses_to_the_same_rows() {
+    let server = MockServer::start().await;
+    let dir = temp_dir("gzip");
+
+    mount_keys(&server, &["/", "/about"]).await;
+    mount_value(&server, "%2F", "42").await;
+    mount_value(&server, "%2Fabout", "7").await;
+
+    // Compressed when asked for, or when the extension is .gz
+    for (file, compress) in [("asked.csv", Some("gzip")), ("inferred.csv.gz", None)] {
+        let mut command = page_tracker(&server, &dir, &["download"]);
+
+        command.arg("--output").arg(dir.join(file));
+
+        if let Some(compress) = compress {
+            command.args(["--compress", compress]);
+        }
+
+        let output = command.output().unwrap();
+
+        assert!(output.status.success(), "{:?}", output);
+
+        let mut rows = String::new();
+
+        GzDecoder::new(fs::File::open(dir.join(file)).unwrap())
+            .read_to_string(&mut rows)
+            .unwrap();
+
+        assert_eq!(rows, "path,views\n/,42\n/about,7\n", "{}", file);
+    }
+}
+
+#[tokio::test]
+async fn exits_with_its_own_status_for_a_missing_key() {
+    let server = MockServer::start().await;
+    let dir = temp_dir("missing-key");
+
+    let output = page_tracker(&server, &dir, &["get"])
+        .args(["--key", "/missing"])
+        .output()
+        .unwrap();
+
+    assert_eq!(output.status.code(), Some(5), "{:?}", output);
+    assert!(String::from_utf8(output.stderr)
+        .unwrap()
+        .contains("Key /missing does not exist"));
+}
+
+#[tokio::test]
+async fn fails_a_download_of_a_value_slower_than_the_per_key_timeout() {
+    let server = MockServer::start().await;
+    let dir = temp_dir("slow-value");
+
+    mount_keys(&server, &["/"]).await;
+    Mock::given(method("GET"))
+        .and(path(format!("{}/values/%2F", NAMESPACE)))
+        .respond_with(
+            ResponseTemplate::new(200)
+                .set_body_string("42")
+                .set_delay(Duration::from_secs(5)),
+        )
+        .mount(&server)
+        .await;
+
+    let output = page_tracker(&server, &dir, &["download"])
+        .arg("--output")
+        .arg(dir.join("views.csv"))
+        .args(["--timeout-per-key", "1", "--max-retries", "0"])
+        .output()
+        .unwrap();
+    let stderr = String::from_utf8(output.stderr).unwrap();
+
+    assert_eq!(output.status.code(), Some(1), "{}", stderr);
+    assert!(stderr.contains("No value within the per-key timeout of 1.0s"));
+    assert!(stderr.contains("1 of 1"), "{}", stderr);
+}
+
+#[tokio::test]
+async fn prunes_encoded_keys_by_their_listed_names() {
+    let server = MockServer::start().await;
+    let dir = temp_dir("prune-encoded");
+
+    mount_keys(&server, &["/a%20b", "/c"]).await;
+    mount_value(&server, "%2Fa%2520b", "1").await;
+    mount_value(&server, "%2Fc", "9").await;
+    Mock::given(method("DELETE"))
+        .and(path(format!("{}/bulk", NAMESPACE)))
+        .and(body_json(json!(["/a%20b"])))
+        .respond_with(ResponseTemplate::new(200).set_body_json(json!({
+            "success": true,
+            "errors": [],
+            "messages": [],
+            "result": null,
+        })))
+        .expect(1)
+        .mount(&server)
+        .await;
+
+    let output = page_tracker(&server, &dir, &["prune"])
+        .args(["--views-below", "5", "--decode-paths", "--confirm"])
+        .output()
+        .unwrap();
+
+    assert!(output.status.success(), "{:?}", output);
+}
+
+#[tokio::test]
+async fn needs_prefixes_to_list_in_parallel() {
+    let server = MockServer::start().await;
+    let dir = temp_dir("parallel-listing");
+
+    let output = page_tracker(&server, &dir, &["--dry-run", "download"])
+        .args(["--list", "--parallel-listing"])
+        .output()
+        .unwrap();
+
+    assert!(!output.status.success());
+    assert!(String::from_utf8(output.stderr)
+        .unwrap()
+        .contains("--prefixes"));
+    assert!(server.received_requests().await.unwrap().is_empty());
+}
+
+#[tokio::test]
+async fn caches_the_pages_left_out_of_the_output() {
+    let server = MockServer::start().await;
+    let dir = temp_dir("cache-filtered");
+    let cache = dir.join("cache.csv");
+    let diff = dir.join("diff.csv");
+
+    for low_views in ["3", "4"] {
+        server.reset().await;
+        mount_keys(&server, &["/", "/low"]).await;
+        mount_value(&server, "%2F", "42").await;
+        mount_value(&server, "%2Flow", low_views).await;
+
+        let output = page_tracker(&server, &dir, &["download"])
+            .arg("--cache")
+            .arg(&cache)
+            .arg("--output")
+            .arg(&diff)
+            .args(["--min-views", "5"])
+            .output()
+            .unwrap();
+
+        assert!(output.status.success(), "{:?}", output);
+    }
+
+    assert_eq!(
+        fs::read_to_string(&cache).unwrap(),
+        "path,views\n/,42\n/low,4\n"
+    );
+    assert_eq!(
+        fs::read_to_string(&diff).unwrap(),
+        "path,status,from,to,change\n/low,increased,3,4,1\n"
+    );
+}
+
+#[tokio::test]
+async fn exits_as_a_network_failure_once_the_circuit_breaker_trips() {
+    let server = MockServer::start().await;
+    let dir = temp_dir("circuit-breaker");
+
+    mount_keys(&server, &["/"]).await;
+    Mock::given(method("GET"))
+        .and(path(format!("{}/values/%2F", NAMESPACE)))
+        .respond_with(ResponseTemplate::new(500))
+        .mount(&server)
+        .await;
+
+    let output = page_tracker(&server, &dir, &["--error-format", "json", "download"])
+        .arg("--output")
+        .arg(dir.join("views.csv"))
+        .args(["--max-failures", "0", "--max-retries", "0"])
+        .output()
+        .unwrap();
+    let stderr = String::from_utf8(output.stderr).unwrap();
+    let error = stderr
+        .lines()
+        .last()
+        .and_then(|line| serde_json::from_str::<Value>(line).ok())
+        .expect("a JSON error line");
+
+    assert_eq!(output.status.code(), Some(3), "{}", stderr);
+    assert_eq!(error["error_kind"], "network");
+    assert_eq!(error["exit_status"], 3);
+}
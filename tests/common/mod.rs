@@ -0,0 +1,34 @@
+use serde_json::{json, Value};
+use wiremock::{
+    matchers::{method, path},
+    Mock, MockServer, ResponseTemplate,
+};
+
+pub const NAMESPACE: &str = "/accounts/account_id/storage/kv/namespaces/kv_id";
+
+/// Page of a key listing, where an empty cursor is the last page
+pub fn keys_page(names: &[&str], cursor: &str) -> Value {
+    json!({
+        "success": true,
+        "errors": [],
+        "messages": [],
+        "result": names.iter().map(|name| json!({ "name": name })).collect::<Vec<_>>(),
+        "result_info": { "cursor": cursor },
+    })
+}
+
+pub async fn mount_keys(server: &MockServer, names: &[&str]) {
+    Mock::given(method("GET"))
+        .and(path(format!("{}/keys", NAMESPACE)))
+        .respond_with(ResponseTemplate::new(200).set_body_json(keys_page(names, "")))
+        .mount(server)
+        .await;
+}
+
+pub async fn mount_value(server: &MockServer, encoded_key: &str, value: &str) {
+    Mock::given(method("GET"))
+        .and(path(format!("{}/values/{}", NAMESPACE, encoded_key)))
+        .respond_with(ResponseTemplate::new(200).set_body_string(value))
+        .mount(server)
+        .await;
+}
@@ -0,0 +1,608 @@
+mod common;
+
+use common::{keys_page, mount_keys, mount_value, NAMESPACE};
+use indicatif::ProgressBar;
+use page_tracker::{
+    download, get_key_value, list_keys, write_records, Auth, CloudflareError, Credential,
+    CsvRecord, DownloadOptions, Downloader, FailedValuesError, FetchedRecords, KeyNotFound,
+    ListOptions, OutputFormat, RetryPolicy, ValueParseError, ValueType, WriteOptions,
+};
+use reqwest::Client;
+use serde_json::json;
+use std::time::{Duration, Instant};
+use wiremock::{
+    matchers::{method, path, query_param, query_param_is_missing},
+    Mock, MockServer, ResponseTemplate,
+};
+
+fn credential(server: &MockServer) -> Credential {
+    (
+        server.uri(),
+        Auth::Token("jwt".to_owned()),
+        "account_id".to_owned(),
+        "kv_id".to_owned(),
+    )
+}
+
+/// Retries without waiting, so the retry tests stay fast
+fn options() -> DownloadOptions {
+    DownloadOptions {
+        retry_policy: RetryPolicy {
+            max_retries: 3,
+            base_delay: Duration::from_millis(1),
+        },
+        ..DownloadOptions::default()
+    }
+}
+
+fn csv(records: &[CsvRecord]) -> String {
+    let mut output = Vec::new();
+
+    write_records(
+        &mut output,
+        OutputFormat::Csv,
+        records,
+        &WriteOptions::default(),
+    )
+    .unwrap();
+
+    String::from_utf8(output).unwrap()
+}
+
+#[tokio::test]
+async fn downloads_every_page_of_keys_into_csv() {
+    let server = MockServer::start().await;
+
+    Mock::given(method("GET"))
+        .and(path(format!("{}/keys", NAMESPACE)))
+        .and(query_param_is_missing("cursor"))
+        .respond_with(ResponseTemplate::new(200).set_body_json(keys_page(&["/", "/about"], "next")))
+        .expect(1)
+        .mount(&server)
+        .await;
+    Mock::given(method("GET"))
+        .and(path(format!("{}/keys", NAMESPACE)))
+        .and(query_param("cursor", "next"))
+        .respond_with(ResponseTemplate::new(200).set_body_json(keys_page(&["/blog/a b"], "")))
+        .expect(1)
+        .mount(&server)
+        .await;
+    mount_value(&server, "%2F", "42").await;
+    mount_value(&server, "%2Fabout", "7").await;
+    mount_value(&server, "%2Fblog%2Fa%20b", "3").await;
+
+    let records = download(&Client::new(), &credential(&server), options())
+        .await
+        .unwrap();
+
+    assert_eq!(csv(&records), "path,views\n/,42\n/about,7\n/blog/a b,3\n");
+}
+
+#[tokio::test]
+async fn fails_with_the_errors_of_a_cloudflare_envelope() {
+    let server = MockServer::start().await;
+
+    Mock::given(method("GET"))
+        .and(path(format!("{}/keys", NAMESPACE)))
+        .respond_with(ResponseTemplate::new(200).set_body_json(json!({
+            "success": false,
+            "errors": [{ "code": 10000, "message": "Authentication error" }],
+            "messages": [],
+            "result": null,
+        })))
+        .mount(&server)
+        .await;
+
+    let err = download(&Client::new(), &credential(&server), options())
+        .await
+        .unwrap_err();
+    let cloudflare = err
+        .downcast_ref::<CloudflareError>()
+        .expect("a Cloudflare error");
+
+    assert_eq!(cloudflare.errors.len(), 1);
+    assert_eq!(cloudflare.errors[0].code, 10000);
+    assert_eq!(cloudflare.errors[0].message, "Authentication error");
+}
+
+#[tokio::test]
+async fn retries_a_rate_limited_value() {
+    let server = MockServer::start().await;
+
+    mount_keys(&server, &["/"]).await;
+    // Mounted first, so it answers the first request before the value mock takes over
+    Mock::given(method("GET"))
+        .and(path(format!("{}/values/%2F", NAMESPACE)))
+        .respond_with(ResponseTemplate::new(429).insert_header("Retry-After", "0"))
+        .up_to_n_times(1)
+        .expect(1)
+        .mount(&server)
+        .await;
+    mount_value(&server, "%2F", "42").await;
+
+    let records = download(&Client::new(), &credential(&server), options())
+        .await
+        .unwrap();
+
+    assert_eq!(csv(&records), "path,views\n/,42\n");
+}
+
+#[tokio::test]
+async fn downloads_nothing_from_an_empty_namespace() {
+    let server = MockServer::start().await;
+
+    mount_keys(&server, &[]).await;
+
+    let records = download(&Client::new(), &credential(&server), options())
+        .await
+        .unwrap();
+
+    assert!(records.is_empty());
+    assert_eq!(csv(&records), "");
+}
+
+#[tokio::test]
+async fn lists_non_ascii_prefixes_in_parallel_in_listing_order() {
+    let server = MockServer::start().await;
+
+    for (prefix, names) in [
+        ("/", &["/", "/a", "/über", "/日本"][..]),
+        ("/ü", &["/über"][..]),
+        ("/日", &["/日本"][..]),
+        ("/a", &["/a"][..]),
+    ] {
+        Mock::given(method("GET"))
+            .and(path(format!("{}/keys", NAMESPACE)))
+            .and(query_param("prefix", prefix))
+            .respond_with(ResponseTemplate::new(200).set_body_json(keys_page(names, "")))
+            .expect(1)
+            .mount(&server)
+            .await;
+    }
+
+    let mut downloader = Downloader::new(
+        &Client::new(),
+        vec![credential(&server)],
+        DownloadOptions {
+            parallel_listing: Some(
+                ["/日", "/ü", "/a", "/"]
+                    .iter()
+                    .map(|prefix| prefix.to_string())
+                    .collect(),
+            ),
+            ..options()
+        },
+    );
+    let namespace_keys = downloader.list_keys().await.unwrap();
+
+    assert_eq!(namespace_keys, [["/", "/a", "/über", "/日本"]]);
+}
+
+#[tokio::test]
+async fn lists_keys_by_following_the_cursor_of_every_page() {
+    let server = MockServer::start().await;
+
+    Mock::given(method("GET"))
+        .and(path(format!("{}/keys", NAMESPACE)))
+        .and(query_param_is_missing("cursor"))
+        .respond_with(
+            ResponseTemplate::new(200).set_body_json(keys_page(&["/", "/about"], "page-2")),
+        )
+        .expect(1)
+        .mount(&server)
+        .await;
+    Mock::given(method("GET"))
+        .and(path(format!("{}/keys", NAMESPACE)))
+        .and(query_param("cursor", "page-2"))
+        .respond_with(ResponseTemplate::new(200).set_body_json(keys_page(&["/blog"], "")))
+        .expect(1)
+        .mount(&server)
+        .await;
+
+    let keys = list_keys(
+        &Client::new(),
+        &credential(&server),
+        &ListOptions::default(),
+        None,
+    )
+    .await
+    .unwrap();
+
+    assert_eq!(
+        keys.iter().map(|key| key.name.as_str()).collect::<Vec<_>>(),
+        ["/", "/about", "/blog"]
+    );
+}
+
+#[tokio::test]
+async fn retries_a_value_until_the_server_recovers() {
+    let server = MockServer::start().await;
+
+    mount_keys(&server, &["/"]).await;
+    Mock::given(method("GET"))
+        .and(path(format!("{}/values/%2F", NAMESPACE)))
+        .respond_with(ResponseTemplate::new(503))
+        .up_to_n_times(2)
+        .expect(2)
+        .mount(&server)
+        .await;
+    Mock::given(method("GET"))
+        .and(path(format!("{}/values/%2F", NAMESPACE)))
+        .respond_with(ResponseTemplate::new(200).set_body_string("42"))
+        .expect(1)
+        .mount(&server)
+        .await;
+
+    let records = download(&Client::new(), &credential(&server), options())
+        .await
+        .unwrap();
+    let attempts = server
+        .received_requests()
+        .await
+        .unwrap()
+        .iter()
+        .filter(|request| request.url.path().contains("/values/"))
+        .count();
+
+    assert_eq!(attempts, 3);
+    assert_eq!(csv(&records), "path,views\n/,42\n");
+}
+
+#[tokio::test]
+async fn lists_keys_under_an_encoded_prefix() {
+    let server = MockServer::start().await;
+
+    Mock::given(method("GET"))
+        .and(path(format!("{}/keys", NAMESPACE)))
+        .and(query_param("prefix", "/blog posts/&?#"))
+        .respond_with(
+            ResponseTemplate::new(200).set_body_json(keys_page(&["/blog posts/&?#a"], "")),
+        )
+        .expect(1)
+        .mount(&server)
+        .await;
+
+    let options = ListOptions {
+        prefix: Some("/blog posts/&?#".to_owned()),
+        ..ListOptions::default()
+    };
+    let keys = list_keys(&Client::new(), &credential(&server), &options, None)
+        .await
+        .unwrap();
+    let requests = server.received_requests().await.unwrap();
+
+    assert_eq!(keys.len(), 1);
+    assert_eq!(
+        requests[0].url.query(),
+        Some("prefix=%2Fblog+posts%2F%26%3F%23")
+    );
+}
+
+#[tokio::test]
+async fn keeps_the_other_values_when_one_fails() {
+    let server = MockServer::start().await;
+
+    mount_keys(&server, &["/", "/about", "/blog"]).await;
+    mount_value(&server, "%2F", "42").await;
+    Mock::given(method("GET"))
+        .and(path(format!("{}/values/%2Fabout", NAMESPACE)))
+        .respond_with(ResponseTemplate::new(500))
+        .mount(&server)
+        .await;
+    mount_value(&server, "%2Fblog", "3").await;
+
+    let mut downloader = Downloader::new(&Client::new(), vec![credential(&server)], options());
+    let namespace_keys = downloader.list_keys().await.unwrap();
+    let FetchedRecords {
+        records,
+        failures,
+        total,
+        ..
+    } = downloader
+        .fetch_records(namespace_keys, &ProgressBar::hidden())
+        .await
+        .unwrap();
+
+    assert_eq!((failures, total), (1, 3));
+    assert_eq!(csv(&records), "path,views\n/,42\n/blog,3\n");
+
+    let err = download(&Client::new(), &credential(&server), options())
+        .await
+        .unwrap_err();
+    let failed = err
+        .downcast_ref::<FailedValuesError>()
+        .expect("a failed values error");
+
+    assert_eq!((failed.failures, failed.total), (1, 3));
+}
+
+#[tokio::test]
+async fn reads_the_result_of_a_successful_cloudflare_envelope() {
+    let server = MockServer::start().await;
+
+    Mock::given(method("GET"))
+        .and(path(format!("{}/keys", NAMESPACE)))
+        .respond_with(ResponseTemplate::new(200).set_body_json(json!({
+            "success": true,
+            "errors": [],
+            "messages": [{ "code": 10100, "message": "Listing is eventually consistent" }],
+            "result": [{ "name": "/", "expiration": 1700000000 }, { "name": "/about" }],
+            "result_info": { "count": 2, "cursor": "" },
+        })))
+        .mount(&server)
+        .await;
+
+    let keys = list_keys(
+        &Client::new(),
+        &credential(&server),
+        &ListOptions::default(),
+        None,
+    )
+    .await
+    .unwrap();
+
+    assert_eq!(
+        keys.iter().map(|key| key.name.as_str()).collect::<Vec<_>>(),
+        ["/", "/about"]
+    );
+}
+
+#[tokio::test]
+async fn describes_every_error_of_a_failed_cloudflare_envelope() {
+    let server = MockServer::start().await;
+
+    Mock::given(method("GET"))
+        .and(path(format!("{}/keys", NAMESPACE)))
+        .respond_with(ResponseTemplate::new(200).set_body_json(json!({
+            "success": false,
+            "errors": [
+                { "code": 10000, "message": "Authentication error" },
+                { "code": 10001, "message": "Unsupported token" },
+            ],
+            "messages": [],
+            "result": null,
+        })))
+        .mount(&server)
+        .await;
+
+    let err = list_keys(
+        &Client::new(),
+        &credential(&server),
+        &ListOptions::default(),
+        None,
+    )
+    .await
+    .unwrap_err();
+
+    let cloudflare = err
+        .downcast_ref::<CloudflareError>()
+        .expect("a Cloudflare error");
+
+    assert_eq!(
+        cloudflare.to_string(),
+        "Cloudflare error 10000: Authentication error; Cloudflare error 10001: Unsupported token"
+    );
+}
+
+#[tokio::test]
+async fn keeps_the_listing_order_of_values_answered_out_of_order() {
+    let server = MockServer::start().await;
+    let keys = ["/a", "/b", "/c", "/d", "/e"];
+
+    mount_keys(&server, &keys).await;
+
+    // Earlier keys answer later, and one of them fails in between
+    for (index, key) in keys.iter().enumerate() {
+        let response = if *key == "/c" {
+            ResponseTemplate::new(404)
+        } else {
+            ResponseTemplate::new(200).set_body_string(index.to_string())
+        };
+
+        Mock::given(method("GET"))
+            .and(path(format!("{}/values/%2F{}", NAMESPACE, &key[1..])))
+            .respond_with(response.set_delay(Duration::from_millis(50 * (5 - index as u64))))
+            .mount(&server)
+            .await;
+    }
+
+    let mut downloader = Downloader::new(
+        &Client::new(),
+        vec![credential(&server)],
+        DownloadOptions {
+            concurrency: keys.len(),
+            ..options()
+        },
+    );
+    let namespace_keys = downloader.list_keys().await.unwrap();
+    let FetchedRecords {
+        records, failures, ..
+    } = downloader
+        .fetch_records(namespace_keys, &ProgressBar::hidden())
+        .await
+        .unwrap();
+
+    assert_eq!(failures, 1);
+    assert_eq!(csv(&records), "path,views\n/a,0\n/b,1\n/d,3\n/e,4\n");
+}
+
+#[tokio::test]
+async fn tells_a_missing_key_from_other_failures() {
+    let server = MockServer::start().await;
+
+    Mock::given(method("GET"))
+        .and(path(format!("{}/values/%2Fmissing", NAMESPACE)))
+        .respond_with(ResponseTemplate::new(404).set_body_json(json!({
+            "success": false,
+            "errors": [{ "code": 10009, "message": "get: 'key not found'" }],
+            "messages": [],
+            "result": null,
+        })))
+        .expect(1)
+        .mount(&server)
+        .await;
+
+    let err = get_key_value(
+        &Client::new(),
+        &credential(&server),
+        "/missing",
+        ValueType::Int,
+    )
+    .await
+    .unwrap_err();
+    let not_found = err
+        .downcast_ref::<KeyNotFound>()
+        .expect("a key not found error");
+
+    assert_eq!(not_found.key, "/missing");
+    assert!(err.downcast_ref::<ValueParseError>().is_none());
+}
+
+#[tokio::test]
+async fn retries_and_then_fails_a_value_slower_than_the_per_key_timeout() {
+    let server = MockServer::start().await;
+
+    mount_keys(&server, &["/", "/slow"]).await;
+    mount_value(&server, "%2F", "42").await;
+    Mock::given(method("GET"))
+        .and(path(format!("{}/values/%2Fslow", NAMESPACE)))
+        .respond_with(
+            ResponseTemplate::new(200)
+                .set_body_string("7")
+                .set_delay(Duration::from_secs(5)),
+        )
+        .expect(2)
+        .mount(&server)
+        .await;
+
+    let mut downloader = Downloader::new(
+        &Client::new(),
+        vec![credential(&server)],
+        DownloadOptions {
+            timeout_per_key: Duration::from_millis(100),
+            retry_policy: RetryPolicy {
+                max_retries: 1,
+                base_delay: Duration::from_millis(1),
+            },
+            ..options()
+        },
+    );
+    let namespace_keys = downloader.list_keys().await.unwrap();
+    let started = Instant::now();
+    let FetchedRecords {
+        records, failures, ..
+    } = downloader
+        .fetch_records(namespace_keys, &ProgressBar::hidden())
+        .await
+        .unwrap();
+
+    assert!(started.elapsed() < Duration::from_secs(5));
+    assert_eq!(failures, 1);
+    assert_eq!(csv(&records), "path,views\n/,42\n");
+}
+
+#[tokio::test]
+async fn reads_values_of_every_value_type() {
+    for (value_type, body) in [
+        (ValueType::Int, "42"),
+        (ValueType::Text, " 0042\n"),
+        (ValueType::Base64Int, "MDA0Mg=="),
+    ] {
+        let server = MockServer::start().await;
+
+        mount_keys(&server, &["/"]).await;
+        mount_value(&server, "%2F", body).await;
+
+        let records = download(
+            &Client::new(),
+            &credential(&server),
+            DownloadOptions {
+                value_type,
+                ..options()
+            },
+        )
+        .await
+        .unwrap();
+
+        assert_eq!(csv(&records), "path,views\n/,42\n", "{:?}", value_type);
+
+        // The body of another type is not a view count
+        let other_type = match value_type {
+            ValueType::Base64Int => ValueType::Text,
+            _ => ValueType::Base64Int,
+        };
+        let err = download(
+            &Client::new(),
+            &credential(&server),
+            DownloadOptions {
+                value_type: other_type,
+                ..options()
+            },
+        )
+        .await
+        .unwrap_err();
+
+        assert!(
+            err.downcast_ref::<FailedValuesError>().is_some(),
+            "{:?}",
+            value_type
+        );
+    }
+}
+
+#[tokio::test]
+async fn fetches_every_key_by_a_single_encoded_path_segment() {
+    let server = MockServer::start().await;
+    let keys = [
+        ("/blog/post", "%2Fblog%2Fpost"),
+        ("/a b", "%2Fa%20b"),
+        ("/über/日本", "%2F%C3%BCber%2F%E6%97%A5%E6%9C%AC"),
+        ("/search?q=1#top", "%2Fsearch%3Fq%3D1%23top"),
+        ("/a%2Fb", "%2Fa%252Fb"),
+        ("...", "%2E%2E%2E"),
+    ];
+
+    for (index, (_, encoded)) in keys.iter().enumerate() {
+        mount_value(&server, encoded, &index.to_string()).await;
+    }
+
+    for (index, (key, _)) in keys.iter().enumerate() {
+        let views = get_key_value(&Client::new(), &credential(&server), key, ValueType::Int)
+            .await
+            .unwrap();
+
+        assert_eq!(views, index as u64, "{}", key);
+    }
+
+    let requests = server.received_requests().await.unwrap();
+
+    for (request, (key, encoded)) in requests.iter().zip(&keys) {
+        assert!(
+            request
+                .url
+                .path()
+                .ends_with(&format!("/values/{}", encoded)),
+            "{} was requested at {}",
+            key,
+            request.url
+        );
+    }
+}
+
+#[tokio::test]
+async fn refuses_keys_that_urls_resolve_to_another_endpoint() {
+    let server = MockServer::start().await;
+
+    for key in ["", ".", ".."] {
+        let err = get_key_value(&Client::new(), &credential(&server), key, ValueType::Int)
+            .await
+            .unwrap_err();
+
+        assert_eq!(
+            err.to_string(),
+            format!("{:?} cannot be used as a key in the URL of a value", key)
+        );
+    }
+
+    assert!(server.received_requests().await.unwrap().is_empty());
+}